@@ -1,156 +1,758 @@
 use crate::{
-    asm::error::AssemblerError, cpu::Cpu, error::CpuError, instruction::Instruction, DEBUG,
+    asm::{
+        error::{AssemblerError, JitError},
+        expr::{EvalError, Expr},
+    },
+    cpu::{AddressMode, Cpu},
+    error::CpuError,
+    instruction::{is_mnemonic, Instruction, Register},
+    DEBUG, MEMORY_SIZE,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
     fs::File,
-    io::{Read, Write},
-    path::Path,
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
 };
 
+/// How deep macros may expand inside each other, which stops a macro that calls itself
+const MAX_MACRO_DEPTH: usize = 32;
+
+/// Where the line being assembled came from, as one of a stack of frames
+#[derive(Debug, Clone)]
+enum Frame {
+    /// Expanding a macro: its name, the file it was defined in if that isn't the one calling it,
+    /// and the line of the call
+    Macro(String, Option<PathBuf>, usize),
+    /// Reading an included file: its path and the line of the `.include`
+    File(PathBuf, usize),
+}
+
+/// An operand whose value wasn't known yet when it was placed
+struct Fixup {
+    offset: usize,
+    width: usize,
+    expression: Expr,
+    line_no: usize,
+    context: Vec<Frame>,
+}
+
+/// A `.macro` definition
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    /// The lines between `.macro` and `.endm`, with their line numbers
+    body: Vec<(String, usize)>,
+    line_no: usize,
+    /// The file it was defined in
+    file: Option<PathBuf>,
+}
+
 pub struct Assembler {
     labels: HashMap<String, usize>,
-    input: File,
+    /// Labels waiting for the next instruction or data, which decides where they point
+    pending_labels: Vec<(String, usize)>,
+    /// `.equ` constants
+    constants: HashMap<String, i64>,
+    input: Box<dyn Read>,
+    /// The file `input` was read from, which `.include` paths are relative to
+    path: Option<PathBuf>,
+    origin: u16,
+    /// The address the next byte is placed at, moved by `.org`
+    location: usize,
     output: Vec<u8>,
-    unknown_labels: HashMap<String, Vec<usize>>,
+    /// Which bytes of `output` were placed, rather than left as a gap between `.org`s
+    used: Vec<bool>,
+    fixups: Vec<Fixup>,
+    /// `.var` declarations in order, as `(name, size, line_no, context)`
+    variables: Vec<(String, usize, usize, Vec<Frame>)>,
+    /// Where `.data` put the data area, directly after the output otherwise
+    data: Option<u16>,
+    macros: HashMap<String, Macro>,
+    /// The macro whose body is being read, between its `.macro` and `.endm`
+    definition: Option<Macro>,
+    /// The macros being expanded and files being included, outermost first
+    context: Vec<Frame>,
+    /// Canonical paths of the files included so far, which later `.include`s skip
+    included: HashSet<PathBuf>,
+    /// How many expansions there have been, which keeps their local labels apart
+    expansions: usize,
 }
 impl Assembler {
     pub fn new(file_handle: File) -> Self {
+        Self::from_reader(file_handle)
+    }
+
+    /// Assemble the file at `path`, resolving its `.include`s relative to it
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let mut assembler = Self::new(File::open(&path)?);
+        assembler.path = Some(path.as_ref().to_owned());
+        Ok(assembler)
+    }
+
+    pub fn from_source(source: &str) -> Self {
+        Self::from_reader(Cursor::new(source.to_owned().into_bytes()))
+    }
+
+    pub fn from_reader<R: Read + 'static>(input: R) -> Self {
         Self {
             labels: HashMap::new(),
-            input: file_handle,
+            pending_labels: vec![],
+            constants: HashMap::new(),
+            input: Box::new(input),
+            path: None,
+            origin: 0,
+            location: 0,
             output: vec![],
-            unknown_labels: HashMap::new(),
+            used: vec![],
+            fixups: vec![],
+            variables: vec![],
+            data: None,
+            macros: HashMap::new(),
+            definition: None,
+            context: vec![],
+            included: HashSet::new(),
+            expansions: 0,
         }
     }
 
-    pub fn jit(file_handle: File, cpu: &mut Cpu) -> Result<(), CpuError> {
+    /// Assemble `source` in one go, returning the image
+    pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+        let mut assembler = Self::from_source(source);
+        assembler.parse()?;
+        Ok(assembler.get_output())
+    }
+
+    /// Assemble for loading at `origin` rather than 0, so labels resolve to where the code will live
+    pub fn with_origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self.location = origin as usize;
+        self
+    }
+
+    /// Assemble the contents of `file_handle` into `cpu` at address 0 and run it
+    pub fn jit(file_handle: File, cpu: &mut Cpu) -> Result<(), JitError> {
+        Self::new(file_handle).jit_into(cpu)
+    }
+
+    /// Assemble, load the output into `cpu` at the origin and run it from there.
+    ///
+    /// Only the bytes covered by the output are written, so data already in memory is kept.
+    pub fn jit_into(mut self, cpu: &mut Cpu) -> Result<(), JitError> {
+        self.parse().map_err(JitError::Assembler)?;
+        self.load_into(cpu).map_err(JitError::Cpu)?;
+        cpu.run().map_err(JitError::Cpu)
+    }
+
+    /// Copy the emitted bytes into `cpu`'s memory and point `ip` at the origin. Gaps left by
+    /// `.org` are skipped so whatever `cpu` already holds there survives
+    pub fn load_into(&self, cpu: &mut Cpu) -> Result<(), CpuError> {
+        for (start, end) in self.spans() {
+            let offset = self.origin as usize + start;
+            if offset > u16::MAX as usize {
+                return Err(CpuError::MemoryError(offset));
+            }
+            cpu.memory.load(offset as u16, &self.output[start..end])?;
+        }
+        cpu.ip = self.origin;
         Ok(())
     }
 
+    /// The runs of output that were actually emitted, as `(start, end)` offsets from the origin
+    fn spans(&self) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+        let mut start = None;
+        for (idx, used) in self.used.iter().enumerate() {
+            match (start, *used) {
+                (None, true) => start = Some(idx),
+                (Some(from), false) => {
+                    spans.push((from, idx));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = start {
+            spans.push((from, self.used.len()));
+        }
+        spans
+    }
+
     pub fn parse(&mut self) -> Result<usize, AssemblerError> {
-        let mut lines = String::new();
-        match self.input.read_to_string(&mut lines) {
+        let mut source = String::new();
+        match self.input.read_to_string(&mut source) {
             Ok(_nbytes) => (),
             Err(e) => return Err(AssemblerError::IOError(e)),
         };
-        let lines = lines.split_terminator('\n');
-        for (line_no, line) in lines
-            .map(|line| line.split_terminator(";;").next().unwrap_or(""))
-            .enumerate()
-        {
-            if line.chars().all(|c| c.is_whitespace()) || line.is_empty() {
-                continue;
-            }
+        self.source(&source)?;
+        // Labels at the end mark the last byte, like a label before an instruction would
+        self.bind_labels(self.here().saturating_sub(1))?;
 
-            if DEBUG {
-                eprintln!("Parsing {:?}", line);
-            }
-            if !" \t".chars().any(|filter| line.starts_with(filter)) {
-                // This _should_ be a label
-                let line = line.trim();
-                if !line.ends_with(':') {
-                    return Err(AssemblerError::UnexpectedInstruction(
-                        line.to_owned(),
-                        line_no,
-                    ));
-                } else {
-                    if DEBUG {
-                        eprintln!(
-                            "Adding label {} -> {}",
-                            line[..line.len() - 1].to_owned(),
-                            self.output.len()
-                        );
-                    }
-                    self.labels.insert(
-                        line[..line.len() - 1].to_owned(),
-                        self.output.len().checked_sub(1).unwrap_or(0),
-                    );
+        let end = self.origin as usize + self.output.len();
+        if end > MEMORY_SIZE {
+            return Err(AssemblerError::ProgramTooLarge(end));
+        }
+        self.allocate_variables()?;
+
+        // Forward references, patched now that every label is known
+        for fixup in std::mem::take(&mut self.fixups) {
+            let value = match fixup.expression.eval(&|name| self.symbol(name)) {
+                Ok(value) => value,
+                Err(EvalError::Undefined(label)) => {
+                    return Err(in_context(
+                        AssemblerError::UndefinedLabel(label, fixup.line_no),
+                        fixup.line_no,
+                        &fixup.context,
+                    ))
                 }
+                Err(EvalError::Invalid(e)) => {
+                    return Err(in_context(
+                        AssemblerError::ExpressionError(e, fixup.line_no),
+                        fixup.line_no,
+                        &fixup.context,
+                    ))
+                }
+            };
+            let bytes = encode(value, fixup.width, fixup.line_no)
+                .map_err(|e| in_context(e, fixup.line_no, &fixup.context))?;
+            self.output[fixup.offset..fixup.offset + fixup.width].copy_from_slice(&bytes);
+        }
+
+        Ok(self.output.len())
+    }
+
+    /// Assemble the lines of a file
+    fn source(&mut self, source: &str) -> Result<(), AssemblerError> {
+        let lines = source.split_terminator('\n');
+        for (line_no, line) in lines.map(strip_comment).enumerate() {
+            self.line(line, line_no)?;
+        }
+        if let Some(definition) = self.definition.take() {
+            return Err(AssemblerError::DirectiveError(
+                format!("macro {} is missing its .endm", definition.name),
+                definition.line_no,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Assemble one line of source, or of a macro body
+    fn line(&mut self, line: &str, line_no: usize) -> Result<(), AssemblerError> {
+        if line.chars().all(|c| c.is_whitespace()) || line.is_empty() {
+            return Ok(());
+        }
+        if self.definition.is_some() {
+            return self.define(line, line_no);
+        }
+
+        if DEBUG {
+            eprintln!("Parsing {:?}", line);
+        }
+        if line.trim_start().starts_with('.') {
+            // Directives may be indented or not
+            self.directive(line.trim(), line_no)?;
+        } else if !" \t".chars().any(|filter| line.starts_with(filter)) {
+            // This _should_ be a label
+            let line = line.trim();
+            if !line.ends_with(':') {
+                return Err(AssemblerError::UnexpectedInstruction(
+                    line.to_owned(),
+                    line_no,
+                ));
             } else {
-                // Instruction
-                let line = line.trim();
-                match Instruction::try_from(line.to_owned()) {
-                    Ok(instruction) => {
-                        let bytes = instruction.as_bytes();
-                        if DEBUG {
-                            eprintln!("For {}, pushing {:?}", line, bytes);
-                        }
-                        self.output.extend(bytes);
+                let label = &line[..line.len() - 1];
+                let defined = self.labels.contains_key(label)
+                    || self
+                        .pending_labels
+                        .iter()
+                        .any(|(pending, _)| pending == label)
+                    || self
+                        .variables
+                        .iter()
+                        .any(|(variable, ..)| variable == label);
+                if defined {
+                    return Err(AssemblerError::DuplicateLabel(label.to_owned(), line_no));
+                }
+                self.pending_labels.push((label.to_owned(), line_no));
+            }
+        } else {
+            // Instruction
+            let line = line.trim();
+            let (mnemonic, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            if self.macros.contains_key(mnemonic) {
+                return self.expand(mnemonic, args.trim(), line_no);
+            }
+            self.bind_labels(self.here().saturating_sub(1))?;
+            match Instruction::try_from(line.to_owned()) {
+                Ok(instruction) => {
+                    let bytes = instruction.as_bytes();
+                    if DEBUG {
+                        eprintln!("For {}, pushing {:?}", line, bytes);
                     }
-                    Err(e) => match e {
-                        Ok((instruction_byte, arg_str)) => {
-                            if let Some(arg_byte) = self.labels.get(&arg_str) {
-                                if DEBUG {
-                                    eprintln!(
-                                        "For {}, pushing [{}, {}]",
-                                        line, instruction_byte, arg_byte
-                                    );
-                                }
-
-                                if let Some(addrs) = self.unknown_labels.remove(&arg_str) {
-                                    for addr in addrs {
-                                        self.output.remove(addr);
-                                        self.output.insert(addr, *arg_byte as u8)
-                                    }
-                                }
-                                self.output.push(instruction_byte);
-                                self.output.push(*arg_byte as u8);
-                            } else {
-                                self.output.push(instruction_byte);
-                                if self.unknown_labels.contains_key(&arg_str) {
-                                    self.unknown_labels
-                                        .get_mut(&arg_str)
-                                        .unwrap()
-                                        .push(self.output.len());
-                                } else {
-                                    self.unknown_labels.insert(arg_str, vec![self.output.len()]);
-                                }
-                                self.output.push(0);
-                            }
-                        }
-                        Err(e) => return Err(AssemblerError::InstructionError(e)),
-                    },
-                }
-            }
-        }
-
-        for (label, addrs) in self.unknown_labels.iter() {
-            if !self.labels.contains_key(label) {
-                return Err(AssemblerError::UndefinedLabel(label.to_owned()));
-            } else {
-                let label_addr = *(self.labels.get(label).unwrap());
-                for addr in addrs {
-                    if label_addr > 255 {
-                        panic!("How the fuck did you get a label outside of memory... dummy")
+                    self.emit(&bytes, line_no)?;
+                }
+                Err(e) => match e {
+                    Ok((bytes, arg_str)) => {
+                        // Whatever of the operand wasn't encoded yet
+                        let width =
+                            Instruction::operand_size(bytes[0], AddressMode::Wide).unwrap_or(0) + 1
+                                - bytes.len();
+                        self.emit(&bytes, line_no)?;
+                        self.emit_expression(&arg_str, width, line_no)?;
                     }
-                    if *addr > self.output.len() {
-                        panic!("How did this even happen. You got an instruction that points to a label to go outta bounds. good for you. Dick.")
+                    Err(e) => return Err(AssemblerError::InstructionError(e, line_no)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Assemble one `.directive` line
+    fn directive(&mut self, line: &str, line_no: usize) -> Result<(), AssemblerError> {
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        let error = |message: String| AssemblerError::DirectiveError(message, line_no);
+        let list = split_args(args);
+
+        let name = name.to_lowercase();
+        if !matches!(
+            name.as_str(),
+            ".org" | ".var" | ".data" | ".equ" | ".macro" | ".endm" | ".include"
+        ) {
+            // A label before data points at the data itself, not the byte before it
+            self.bind_labels(self.here())?;
+        }
+        match name.as_str() {
+            ".org" => {
+                let addr = self.value(args, 2, line_no)? as u16;
+                if addr < self.origin {
+                    return Err(error(format!(
+                        ".org 0x{:04X} is below the origin 0x{:04X}",
+                        addr, self.origin
+                    )));
+                }
+                self.location = addr as usize;
+            }
+            ".byte" => {
+                for arg in list {
+                    self.emit_expression(arg, 1, line_no)?;
+                }
+            }
+            ".word" => {
+                for arg in list {
+                    self.emit_expression(arg, 2, line_no)?;
+                }
+            }
+            ".string" => {
+                let mut bytes = parse_string(args).map_err(error)?;
+                bytes.push(0);
+                self.emit(&bytes, line_no)?;
+            }
+            ".fill" => {
+                let (count, value) = match list.as_slice() {
+                    [count] if !count.is_empty() => (self.value(count, 2, line_no)?, 0),
+                    [count, value] => (
+                        self.value(count, 2, line_no)?,
+                        self.value(value, 1, line_no)?,
+                    ),
+                    _ => return Err(error("expected .fill count[, value]".to_owned())),
+                };
+                self.emit(&vec![value as u8; count as usize], line_no)?;
+            }
+            ".var" => {
+                let (variable, size) = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+                    [variable] => (variable, 1),
+                    [variable, size] => (variable, self.value(size, 2, line_no)?),
+                    _ => return Err(error("expected .var name [size]".to_owned())),
+                };
+                if !is_name(variable) {
+                    return Err(error(format!("{} is not a valid name", variable)));
+                }
+                if size == 0 {
+                    return Err(error(format!("{} needs at least one byte", variable)));
+                }
+                if self.is_defined(variable) {
+                    return Err(error(format!("{} is already defined", variable)));
+                }
+                self.variables.push((
+                    variable.to_owned(),
+                    size as usize,
+                    line_no,
+                    self.context.clone(),
+                ));
+            }
+            ".data" => self.data = Some(self.value(args, 2, line_no)? as u16),
+            ".equ" => {
+                let (constant, value) = match args.split_once(char::is_whitespace) {
+                    Some((constant, value)) => (constant, value.trim()),
+                    None => return Err(error("expected .equ name value".to_owned())),
+                };
+                if !is_name(constant) {
+                    return Err(error(format!("{} is not a valid name", constant)));
+                }
+                if self.is_defined(constant) {
+                    return Err(error(format!("{} is already defined", constant)));
+                }
+                let value = self.evaluate(value, line_no)?;
+                self.constants.insert(constant.to_owned(), value);
+            }
+            ".macro" => {
+                let (name, params) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let params = params
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|param| !param.is_empty())
+                    .map(str::to_owned)
+                    .collect::<Vec<String>>();
+                for name in params.iter().map(String::as_str).chain([name]) {
+                    if !is_name(name) || name.starts_with('.') {
+                        return Err(error(format!("{:?} is not a valid name", name)));
                     }
-                    self.output.remove(*addr);
-                    self.output.insert(*addr, label_addr as u8);
                 }
+                // Substituting these would rewrite operands like `base,U` or `MOV R0, A`
+                if let Some(param) = params.iter().find(|param| Register::parse(param).is_some()) {
+                    return Err(error(format!("register {} can't be a parameter", param)));
+                }
+                // Lines starting with it would expand the macro instead of assembling
+                if is_mnemonic(name) {
+                    return Err(error(format!("instruction {} can't be a macro name", name)));
+                }
+                if self.macros.contains_key(name) {
+                    return Err(error(format!("macro {} is already defined", name)));
+                }
+                self.definition = Some(Macro {
+                    name: name.to_owned(),
+                    params,
+                    body: vec![],
+                    line_no,
+                    file: self.file().map(Path::to_owned),
+                });
+            }
+            ".endm" => return Err(error(".endm without a .macro".to_owned())),
+            ".include" => {
+                let include = args
+                    .strip_prefix('"')
+                    .and_then(|args| args.strip_suffix('"'))
+                    .filter(|include| !include.is_empty())
+                    .ok_or_else(|| error("expected .include \"path\"".to_owned()))?;
+                let path = match self.file().and_then(Path::parent) {
+                    Some(dir) => dir.join(include),
+                    None => PathBuf::from(include),
+                };
+                self.include(path, line_no)?;
             }
+            _ => return Err(error(format!("unknown directive {}", name))),
         }
+        Ok(())
+    }
 
-        Ok(self.output.len())
+    /// Add a line to the macro being defined, or finish it at `.endm`
+    fn define(&mut self, line: &str, line_no: usize) -> Result<(), AssemblerError> {
+        let directive = line.split_whitespace().next().unwrap_or("").to_lowercase();
+        let definition = self.definition.as_mut().unwrap();
+        match directive.as_str() {
+            ".endm" => {
+                let definition = self.definition.take().unwrap();
+                self.macros.insert(definition.name.clone(), definition);
+            }
+            ".macro" => {
+                return Err(AssemblerError::DirectiveError(
+                    format!(
+                        "macro definitions can't nest, {} is still open",
+                        definition.name
+                    ),
+                    line_no,
+                ))
+            }
+            _ => definition.body.push((line.to_owned(), line_no)),
+        }
+        Ok(())
     }
 
+    /// Assemble the body of macro `name` in place of the call on `line_no`
+    fn expand(&mut self, name: &str, args: &str, line_no: usize) -> Result<(), AssemblerError> {
+        let definition = &self.macros[name];
+        let args = match args {
+            "" => vec![],
+            args => split_args(args),
+        };
+        if args.len() != definition.params.len() {
+            return Err(AssemblerError::DirectiveError(
+                format!(
+                    "macro {} takes {} argument(s), found {}",
+                    name,
+                    definition.params.len(),
+                    args.len()
+                ),
+                line_no,
+            ));
+        }
+        let depth = self
+            .context
+            .iter()
+            .filter(|frame| matches!(frame, Frame::Macro(..)))
+            .count();
+        if depth == MAX_MACRO_DEPTH {
+            return Err(AssemblerError::DirectiveError(
+                format!("macro {} nests more than {} deep", name, MAX_MACRO_DEPTH),
+                line_no,
+            ));
+        }
+
+        // Parameters become their arguments, and labels in the body get a name of their own
+        self.expansions += 1;
+        let mut names = definition
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(args.into_iter().map(str::to_owned))
+            .collect::<HashMap<&str, String>>();
+        for (line, _) in &definition.body {
+            if !line.starts_with([' ', '\t', '.']) {
+                if let Some(label) = line.trim().strip_suffix(':') {
+                    names.insert(label, format!("{}.{}.{}", name, label, self.expansions));
+                }
+            }
+        }
+        let body = definition
+            .body
+            .iter()
+            .map(|(line, body_line)| (substitute(line, &names), *body_line))
+            .collect::<Vec<(String, usize)>>();
+
+        // Only worth naming in errors if the macro came from another file
+        let file = match definition.file.as_deref() {
+            file if file != self.file() => file.map(Path::to_owned),
+            _ => None,
+        };
+
+        self.context
+            .push(Frame::Macro(name.to_owned(), file.clone(), line_no));
+        for (line, body_line) in body {
+            self.line(&line, body_line).map_err(|e| {
+                AssemblerError::InMacro(
+                    Box::new(e),
+                    name.to_owned(),
+                    file.clone(),
+                    body_line,
+                    line_no,
+                )
+            })?;
+        }
+        self.context.pop();
+        Ok(())
+    }
+
+    /// Assemble the file at `path` in place of the `.include` on `line_no`
+    fn include(&mut self, path: PathBuf, line_no: usize) -> Result<(), AssemblerError> {
+        let included = |e| AssemblerError::Included(Box::new(e), path.clone(), line_no);
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| included(AssemblerError::IOError(e)))?;
+        let mut open = self
+            .context
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::File(path, _) => Some(path.as_path()),
+                Frame::Macro(..) => None,
+            })
+            .chain(self.path.as_deref());
+        if open.any(|open| open.canonicalize().is_ok_and(|open| open == canonical)) {
+            return Err(AssemblerError::DirectiveError(
+                format!("{} is already being included", path.display()),
+                line_no,
+            ));
+        }
+        // Two files sharing a library both include it, but its definitions only count once
+        if self.included.contains(&canonical) {
+            return Ok(());
+        }
+        let source =
+            std::fs::read_to_string(&path).map_err(|e| included(AssemblerError::IOError(e)))?;
+
+        self.context.push(Frame::File(path.clone(), line_no));
+        self.source(&source).map_err(included)?;
+        self.context.pop();
+        self.included.insert(canonical);
+        Ok(())
+    }
+
+    /// The file the line being assembled is in
+    fn file(&self) -> Option<&Path> {
+        for frame in self.context.iter().rev() {
+            match frame {
+                Frame::File(path, _) | Frame::Macro(_, Some(path), _) => return Some(path),
+                // Defined in the same file as the caller
+                Frame::Macro(_, None, _) => (),
+            }
+        }
+        self.path.as_deref()
+    }
+
+    /// The value of a symbol: a constant, a label, or a variable once they are allocated
+    fn symbol(&self, name: &str) -> Option<i64> {
+        match self.constants.get(name) {
+            Some(value) => Some(*value),
+            None => self.labels.get(name).map(|addr| *addr as i64),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.symbol(name).is_some()
+            || self.pending_labels.iter().any(|(label, _)| label == name)
+            || self.variables.iter().any(|(variable, ..)| variable == name)
+    }
+
+    /// Evaluate `arg` now, so it may only use symbols defined above it
+    fn evaluate(&self, arg: &str, line_no: usize) -> Result<i64, AssemblerError> {
+        let expression =
+            Expr::parse(arg).map_err(|e| AssemblerError::ExpressionError(e, line_no))?;
+        match expression.eval(&|name| self.symbol(name)) {
+            Ok(value) => Ok(value),
+            Err(EvalError::Undefined(name)) => Err(AssemblerError::ExpressionError(
+                format!("{} must be defined before it is used here", name),
+                line_no,
+            )),
+            Err(EvalError::Invalid(e)) => Err(AssemblerError::ExpressionError(e, line_no)),
+        }
+    }
+
+    /// Evaluate `arg` now, as an unsigned value of `width` bytes
+    fn value(&self, arg: &str, width: usize, line_no: usize) -> Result<i64, AssemblerError> {
+        let value = self.evaluate(arg, line_no)?;
+        if value < 0 || value >= 1 << (8 * width) {
+            return Err(AssemblerError::ExpressionError(
+                format!(
+                    "{} is {}, which doesn't fit in {} byte(s)",
+                    arg, value, width
+                ),
+                line_no,
+            ));
+        }
+        Ok(value)
+    }
+
+    /// Give every `.var` an address in the data area, in the order they were declared
+    fn allocate_variables(&mut self) -> Result<(), AssemblerError> {
+        let origin = self.origin as usize;
+        let mut addr = match self.data {
+            Some(data) => data as usize,
+            None => origin + self.output.len(),
+        };
+        for (name, size, line_no, context) in std::mem::take(&mut self.variables) {
+            if self.symbol(&name).is_some() {
+                return Err(in_context(
+                    AssemblerError::DirectiveError(
+                        format!("{} is both a label and a variable", name),
+                        line_no,
+                    ),
+                    line_no,
+                    &context,
+                ));
+            }
+            let end = addr + size;
+            if end > MEMORY_SIZE {
+                return Err(AssemblerError::ProgramTooLarge(end));
+            }
+            let taken = (addr.max(origin)..end)
+                .find(|addr| self.used.get(addr - origin).copied().unwrap_or(false));
+            if let Some(taken) = taken {
+                return Err(in_context(
+                    AssemblerError::DataOverlap(name, taken, line_no),
+                    line_no,
+                    &context,
+                ));
+            }
+            self.labels.insert(name, addr);
+            addr = end;
+        }
+        Ok(())
+    }
+
+    /// Point every pending label at `addr`
+    fn bind_labels(&mut self, addr: usize) -> Result<(), AssemblerError> {
+        for (label, line_no) in std::mem::take(&mut self.pending_labels) {
+            if self.constants.contains_key(&label) {
+                return Err(AssemblerError::DirectiveError(
+                    format!("{} is both a label and a constant", label),
+                    line_no,
+                ));
+            }
+            if DEBUG {
+                eprintln!("Adding label {} -> {}", label, addr);
+            }
+            self.labels.insert(label, addr);
+        }
+        Ok(())
+    }
+
+    /// Place `bytes` at the location counter and move past them
+    fn emit(&mut self, bytes: &[u8], line_no: usize) -> Result<(), AssemblerError> {
+        let start = self.location - self.origin as usize;
+        let end = start + bytes.len();
+        if end > self.output.len() {
+            self.output.resize(end, 0);
+            self.used.resize(end, false);
+        }
+        if let Some(taken) = self.used[start..end].iter().position(|used| *used) {
+            return Err(AssemblerError::Overlap(self.location + taken, line_no));
+        }
+        self.output[start..end].copy_from_slice(bytes);
+        self.used[start..end].fill(true);
+        self.location += bytes.len();
+        Ok(())
+    }
+
+    /// Place `arg` as a `width` byte operand, patched at the end if it uses labels that aren't
+    /// known yet
+    fn emit_expression(
+        &mut self,
+        arg: &str,
+        width: usize,
+        line_no: usize,
+    ) -> Result<(), AssemblerError> {
+        let expression =
+            Expr::parse(arg).map_err(|e| AssemblerError::ExpressionError(e, line_no))?;
+        match expression.eval(&|name| self.symbol(name)) {
+            Ok(value) => {
+                let bytes = encode(value, width, line_no)?;
+                self.emit(&bytes, line_no)
+            }
+            Err(EvalError::Undefined(_)) => {
+                self.fixups.push(Fixup {
+                    offset: self.location - self.origin as usize,
+                    width,
+                    expression,
+                    line_no,
+                    context: self.context.clone(),
+                });
+                self.emit(&vec![0; width], line_no)
+            }
+            Err(EvalError::Invalid(e)) => Err(AssemblerError::ExpressionError(e, line_no)),
+        }
+    }
+
+    /// The address the next assembled byte will be loaded at
+    fn here(&self) -> usize {
+        self.location
+    }
+
+    /// Run the output on a fresh cpu, loaded at the origin
     pub fn run(&self) -> Result<(), CpuError> {
-        let mut cpu = Cpu::new();
-        cpu.memory = crate::memory::Memory::new_with_instructions(&self.output);
-        cpu.run()
+        self.load_and_run(&mut Cpu::new())
     }
 
-    pub fn load_and_run(&self, cpu_handle: &mut Cpu) -> Result<(), CpuError> {
-        cpu_handle.memory = crate::memory::Memory::new_with_instructions(&self.output);
-        cpu_handle.run()
+    /// Load the output into `cpu` at the origin and run it. The rest of its memory, and any ROM
+    /// or devices mapped there, are left alone.
+    pub fn load_and_run(&self, cpu: &mut Cpu) -> Result<(), CpuError> {
+        self.load_into(cpu)?;
+        cpu.run()
     }
 
     pub fn output_to_file<P: AsRef<Path>>(self, path: P) -> std::io::Result<()> {
-        let mut file_handle = File::open(path)?;
-        file_handle.write(&self.output)?;
+        let mut file_handle = File::create(path)?;
+        file_handle.write_all(&self.output)?;
         Ok(())
     }
 
@@ -158,3 +760,157 @@ impl Assembler {
         self.output
     }
 }
+
+/// `value` as `width` little-endian bytes. Negative values are two's complement, so a byte
+/// operand takes anything from -128 to 255.
+fn encode(value: i64, width: usize, line_no: usize) -> Result<Vec<u8>, AssemblerError> {
+    let bits = 8 * width as u32;
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(AssemblerError::ExpressionError(
+            format!("{} doesn't fit in a {}-bit operand", value, bits),
+            line_no,
+        ));
+    }
+    Ok(value.to_le_bytes()[..width].to_vec())
+}
+
+/// `error` from `line_no` of the innermost frame of `context`, wrapped in where each frame was
+/// called or included from
+fn in_context(mut error: AssemblerError, mut line_no: usize, context: &[Frame]) -> AssemblerError {
+    for frame in context.iter().rev() {
+        error = match frame {
+            Frame::Macro(name, file, call_line) => {
+                let body_line = std::mem::replace(&mut line_no, *call_line);
+                AssemblerError::InMacro(
+                    Box::new(error),
+                    name.clone(),
+                    file.clone(),
+                    body_line,
+                    *call_line,
+                )
+            }
+            Frame::File(path, include_line) => {
+                line_no = *include_line;
+                AssemblerError::Included(Box::new(error), path.clone(), *include_line)
+            }
+        };
+    }
+    error
+}
+
+/// `line` with every name found in `names` replaced, outside of strings
+fn substitute(line: &str, names: &HashMap<&str, String>) -> String {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    let mut result = String::with_capacity(line.len());
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = if quoted || !is_name_char(c) {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                _ => (),
+            }
+            result.push(c);
+            c.len_utf8()
+        } else {
+            let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            match names.get(&rest[..len]) {
+                Some(replacement) => result.push_str(replacement),
+                None => result.push_str(&rest[..len]),
+            }
+            len
+        };
+        rest = &rest[len..];
+    }
+    result
+}
+
+/// A label, variable or constant name: letters, digits, `_` and `.`, not starting with a digit
+fn is_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// `line` up to its `;;` comment, if it has one outside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted && line[idx..].starts_with(";;") => return &line[..idx],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// `args` split at the commas outside of strings, each trimmed
+fn split_args(args: &str) -> Vec<&str> {
+    let mut list = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in args.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                list.push(args[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    list.push(args[start..].trim());
+    list
+}
+
+/// Assemble `source`, panicking with the error and the source if it doesn't assemble
+#[cfg(test)]
+pub(crate) fn assemble_str(source: &str) -> Vec<u8> {
+    match Assembler::assemble(source) {
+        Ok(output) => output,
+        Err(e) => panic!("{}\n{}", e, source),
+    }
+}
+
+/// The bytes of a double-quoted string, with `\n`, `\t`, `\0`, `\\` and `\"` escapes
+fn parse_string(arg: &str) -> Result<Vec<u8>, String> {
+    let inner = arg
+        .strip_prefix('"')
+        .and_then(|arg| arg.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found {}", arg))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(other) => return Err(format!("unknown escape \\{} in {}", other, arg)),
+                None => return Err(format!("unterminated string {}", arg)),
+            },
+            '"' => return Err(format!("unescaped quote in {}", arg)),
+            c => c,
+        };
+        if !c.is_ascii() {
+            return Err(format!("{:?} is not ASCII", c));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
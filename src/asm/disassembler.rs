@@ -0,0 +1,168 @@
+use crate::{asm::error::DisassemblerError, cpu::AddressMode, instruction::Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A decoded line of the image
+enum Item {
+    Code(Instruction),
+    Data(u8),
+}
+
+pub struct Disassembler {
+    input: Vec<u8>,
+    mode: AddressMode,
+}
+impl Disassembler {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            input: bytes,
+            mode: AddressMode::Wide,
+        }
+    }
+
+    /// Decode an image built for `mode`, such as an 8-bit binary with `AddressMode::Narrow`
+    pub fn with_mode(mut self, mode: AddressMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Decode the whole image into `(address, instruction)` pairs, failing on anything that
+    /// isn't an instruction
+    pub fn decode(&self) -> Result<Vec<(usize, Instruction)>, DisassemblerError> {
+        let mut decoded = Vec::new();
+        let mut addr = 0;
+        while addr < self.input.len() {
+            let (instruction, size) = self.decode_at(addr)?;
+            decoded.push((addr, instruction));
+            addr += size;
+        }
+        Ok(decoded)
+    }
+
+    /// The instruction at `addr` and how many bytes it takes up
+    fn decode_at(&self, addr: usize) -> Result<(Instruction, usize), DisassemblerError> {
+        let byte = self.input[addr];
+        let size = match Instruction::operand_size(byte, self.mode) {
+            Some(size) => size,
+            None => return Err(DisassemblerError::UnknownOpcode(addr, byte)),
+        };
+        let operand = match self.input.get(addr + 1..addr + 1 + size) {
+            Some(operand) => operand,
+            None => return Err(DisassemblerError::TruncatedInstruction(addr, byte)),
+        };
+        match Instruction::decode(byte, operand) {
+            Ok(instruction) => Ok((instruction, 1 + size)),
+            Err(_) => Err(DisassemblerError::UnknownOpcode(addr, byte)),
+        }
+    }
+
+    /// Like `decode`, but a byte that doesn't start an instruction is kept as data
+    fn decode_with_data(&self) -> Vec<(usize, Item)> {
+        let mut decoded = Vec::new();
+        let mut addr = 0;
+        while addr < self.input.len() {
+            match self.decode_at(addr) {
+                Ok((instruction, size)) => {
+                    decoded.push((addr, Item::Code(instruction)));
+                    addr += size;
+                }
+                Err(_) => {
+                    decoded.push((addr, Item::Data(self.input[addr])));
+                    addr += 1;
+                }
+            }
+        }
+        decoded
+    }
+
+    /// Produce assembler source for the image, with a label synthesized for every jump target.
+    /// Bytes that don't decode become `.byte` lines.
+    pub fn disassemble(&self) -> Result<String, DisassemblerError> {
+        let decoded = self.decode_with_data();
+
+        // Instructions are only ever entered at these addresses, plus the end of the image,
+        // and a label before data resolves to the data itself, so those get none
+        let boundaries: BTreeSet<usize> = decoded
+            .iter()
+            .filter(|(_, item)| matches!(item, Item::Code(_)))
+            .map(|(addr, _)| *addr)
+            .chain(std::iter::once(self.input.len()))
+            .collect();
+
+        // The cpu increments `ip` after executing a jump, so a jump to `v` resumes at `v + 1`,
+        // and the assembler resolves a label to the address just before it.
+        let mut labels = BTreeMap::new();
+        for (_, item) in decoded.iter() {
+            let instruction = match item {
+                Item::Code(instruction) => instruction,
+                Item::Data(_) => continue,
+            };
+            if let Some(target) = Self::jump_target(instruction) {
+                let resume = target as usize + 1;
+                if boundaries.contains(&resume) {
+                    labels.insert(resume, format!("label_{}", resume));
+                } else if target == 0 {
+                    // A label on the very first instruction also resolves to 0
+                    labels.insert(0, "label_0".to_owned());
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        for (addr, item) in decoded.iter() {
+            if let Some(label) = labels.get(addr) {
+                lines.push(format!("{}:", label));
+            }
+            let text = match item {
+                Item::Data(byte) => format!(".byte 0x{:02X}", byte),
+                Item::Code(instruction) => match Self::jump_target(instruction) {
+                    Some(target) => match Self::label_for(&labels, target) {
+                        Some(label) => format!("{} {}", Self::mnemonic(instruction), label),
+                        None => String::from(*instruction),
+                    },
+                    None => String::from(*instruction),
+                },
+            };
+            lines.push(format!("    {:<16};; 0x{:04X}", text, addr));
+        }
+        if let Some(label) = labels.get(&self.input.len()) {
+            lines.push(format!("{}:", label));
+        }
+
+        Ok(lines.join("\n") + "\n")
+    }
+
+    fn jump_target(instruction: &Instruction) -> Option<u16> {
+        match instruction {
+            Instruction::JMP(v)
+            | Instruction::JC(v)
+            | Instruction::JCS(v)
+            | Instruction::JZ(v)
+            | Instruction::JO(v)
+            | Instruction::JNZ(v)
+            | Instruction::JNO(v)
+            | Instruction::JNC(v)
+            | Instruction::JN(v)
+            | Instruction::JNN(v)
+            | Instruction::JLT(v)
+            | Instruction::JGE(v)
+            | Instruction::JGT(v)
+            | Instruction::JLE(v)
+            | Instruction::CALL(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn label_for(labels: &BTreeMap<usize, String>, target: u16) -> Option<&String> {
+        labels
+            .get(&(target as usize + 1))
+            .or_else(|| if target == 0 { labels.get(&0) } else { None })
+    }
+
+    fn mnemonic(instruction: &Instruction) -> String {
+        String::from(*instruction)
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_owned()
+    }
+}
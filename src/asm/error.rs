@@ -1,9 +1,135 @@
-use std::io::Error;
+use crate::error::CpuError;
+use std::{io::Error, path::PathBuf};
 
 #[derive(Debug)]
 pub enum AssemblerError {
     IOError(Error),
     UnexpectedInstruction(String, usize),
-    InstructionError(String),
-    UndefinedLabel(String),
+    InstructionError(String, usize),
+    /// A label that is used, on the line given, but never defined
+    UndefinedLabel(String, usize),
+    /// A label defined again, on the line given
+    DuplicateLabel(String, usize),
+    ProgramTooLarge(usize),
+    /// A malformed directive, and the line it is on
+    DirectiveError(String, usize),
+    /// Output placed at an address something earlier already took
+    Overlap(usize, usize),
+    /// A variable whose storage, at the address given, is already taken by the output
+    DataOverlap(String, usize, usize),
+    /// An operand or directive argument that can't be evaluated, or doesn't fit
+    ExpressionError(String, usize),
+    /// An error in a macro expansion: the error, the macro, the file it was defined in if that
+    /// isn't the one calling it, the line of its body and the line it was called on
+    InMacro(Box<AssemblerError>, String, Option<PathBuf>, usize, usize),
+    /// An error in an included file: the error, the file and the line of the `.include`
+    Included(Box<AssemblerError>, PathBuf, usize),
 }
+impl std::fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AssemblerError::IOError(e) => write!(f, "failed to read input: {}", e),
+            AssemblerError::UnexpectedInstruction(line, line_no) => write!(
+                f,
+                "line {}: expected a label, found {:?} (instructions must be indented)",
+                line_no + 1,
+                line
+            ),
+            AssemblerError::InstructionError(e, line_no) => {
+                write!(f, "line {}: {}", line_no + 1, e)
+            }
+            AssemblerError::UndefinedLabel(label, line_no) => {
+                write!(f, "line {}: undefined label {:?}", line_no + 1, label)
+            }
+            AssemblerError::DuplicateLabel(label, line_no) => {
+                write!(
+                    f,
+                    "line {}: label {:?} is already defined",
+                    line_no + 1,
+                    label
+                )
+            }
+            AssemblerError::ProgramTooLarge(end) => write!(
+                f,
+                "program ends at 0x{:X}, past the end of memory (0x{:X})",
+                end,
+                crate::MEMORY_SIZE
+            ),
+            AssemblerError::DirectiveError(e, line_no) => {
+                write!(f, "line {}: {}", line_no + 1, e)
+            }
+            AssemblerError::Overlap(addr, line_no) => write!(
+                f,
+                "line {}: 0x{:04X} is already taken by earlier output",
+                line_no + 1,
+                addr
+            ),
+            AssemblerError::DataOverlap(name, addr, line_no) => write!(
+                f,
+                "line {}: variable {:?} overlaps the output at 0x{:04X}",
+                line_no + 1,
+                name,
+                addr
+            ),
+            AssemblerError::ExpressionError(e, line_no) => {
+                write!(f, "line {}: {}", line_no + 1, e)
+            }
+            AssemblerError::InMacro(e, name, file, body_line, call_line) => {
+                write!(f, "{} (in macro {} at ", e, name)?;
+                if let Some(file) = file {
+                    write!(f, "{} ", file.display())?;
+                }
+                write!(
+                    f,
+                    "line {}, called on line {})",
+                    body_line + 1,
+                    call_line + 1
+                )
+            }
+            AssemblerError::Included(e, path, line_no) => write!(
+                f,
+                "{}: {} (included on line {})",
+                path.display(),
+                e,
+                line_no + 1
+            ),
+        }
+    }
+}
+impl std::error::Error for AssemblerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisassemblerError {
+    UnknownOpcode(usize, u8),
+    TruncatedInstruction(usize, u8),
+}
+impl std::fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisassemblerError::UnknownOpcode(addr, byte) => {
+                write!(f, "unknown opcode 0x{:02X} at 0x{:04X}", byte, addr)
+            }
+            DisassemblerError::TruncatedInstruction(addr, byte) => write!(
+                f,
+                "instruction 0x{:02X} at 0x{:04X} is missing its argument",
+                byte, addr
+            ),
+        }
+    }
+}
+impl std::error::Error for DisassemblerError {}
+
+#[derive(Debug)]
+pub enum JitError {
+    Assembler(AssemblerError),
+    Cpu(CpuError),
+}
+impl std::fmt::Display for JitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JitError::Assembler(e) => write!(f, "{}", e),
+            JitError::Cpu(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for JitError {}
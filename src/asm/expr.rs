@@ -0,0 +1,242 @@
+//! Operand expressions: numbers, symbols and parentheses, the binary operators
+//! `* / % + - << >> & ^ |` with C precedence, and the unary `-`, `~`, `<` (low byte) and
+//! `>` (high byte).
+
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+}
+impl BinaryOp {
+    fn parse(token: &str) -> Option<Self> {
+        let op = match token {
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            "&" => BinaryOp::And,
+            "^" => BinaryOp::Xor,
+            "|" => BinaryOp::Or,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// Higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 5,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Shl | BinaryOp::Shr => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Xor => 1,
+            BinaryOp::Or => 0,
+        }
+    }
+}
+
+/// Why an expression has no value
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// A symbol that isn't defined, or not yet
+    Undefined(String),
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?} in {}", token, source)),
+        }
+    }
+
+    /// The value of the expression, with the value of each symbol from `lookup`
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, EvalError> {
+        let value = match self {
+            Expr::Number(value) => *value,
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| EvalError::Undefined(name.clone()))?,
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(lookup)?;
+                match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => !value,
+                    UnaryOp::Low => value & 0xFF,
+                    UnaryOp::High => (value >> 8) & 0xFF,
+                }
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(lookup)?, rhs.eval(lookup)?);
+                let shift = || {
+                    u32::try_from(rhs)
+                        .ok()
+                        .filter(|shift| *shift < 64)
+                        .ok_or_else(|| EvalError::Invalid(format!("can't shift by {}", rhs)))
+                };
+                match op {
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        return Err(EvalError::Invalid("division by zero".to_owned()))
+                    }
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Rem => lhs.wrapping_rem(rhs),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Shl => lhs << shift()?,
+                    BinaryOp::Shr => lhs >> shift()?,
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                    BinaryOp::Or => lhs | rhs,
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Binary operators binding at least as tight as `min_precedence`, all left associative
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(token)) = self.tokens.get(self.pos) {
+            let op = match BinaryOp::parse(token) {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(op.precedence() + 1)?;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Symbol(name)) => Ok(Expr::Symbol(name)),
+            Some(Token::Open) => {
+                let expr = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("expected )".to_owned()),
+                }
+            }
+            Some(Token::Op(token)) => {
+                let op = match token {
+                    "-" => UnaryOp::Neg,
+                    "~" => UnaryOp::Not,
+                    "<" => UnaryOp::Low,
+                    ">" => UnaryOp::High,
+                    _ => return Err(format!("expected a value, found {}", token)),
+                };
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Some(Token::Close) => Err("unexpected )".to_owned()),
+            None => Err("expected a value".to_owned()),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    // Longest first, so `<<` isn't read as two `<`
+    const OPS: [&str; 13] = [
+        "<<", ">>", "*", "/", "%", "+", "-", "&", "^", "|", "~", "<", ">",
+    ];
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                tokens.push(Token::Number(parse_number(word)?));
+            } else {
+                tokens.push(Token::Symbol(word.to_owned()));
+            }
+            len
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            1
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            1
+        } else {
+            match OPS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                }
+                None => return Err(format!("unexpected {:?} in {}", c, source)),
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<i64, String> {
+    let parsed = if let Some(hex) = word.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = word.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else {
+        word.parse()
+    };
+    parsed.map_err(|e| format!("Encountered an error parsing {}: {}", word, e))
+}
@@ -1,9 +1,13 @@
-pub(crate) mod assembler;
-pub(crate) mod error;
+pub mod assembler;
+pub mod disassembler;
+pub mod error;
+mod expr;
 
 #[cfg(test)]
+#[allow(dead_code)]
 mod tests {
     use super::*;
+    use assembler::assemble_str;
     use std::fs::File;
 
     pub const NOP: u8 = 0x00;
@@ -20,35 +24,87 @@ mod tests {
     pub const JMP: u8 = 0x0B;
     pub const JC: u8 = 0x0C;
     pub const JZ: u8 = 0x0E;
+    pub const JNZ: u8 = 0x0D;
     pub const JO: u8 = 0x0F;
     pub const OUT: u8 = 0x10;
     pub const EXIT: u8 = 0x11;
     pub const CLN: u8 = 0x12;
+    pub const IN: u8 = 0x13;
+    pub const PUSHA: u8 = 0x14;
+    pub const PUSHU: u8 = 0x15;
+    pub const POPA: u8 = 0x16;
+    pub const POPU: u8 = 0x17;
+    pub const CALL: u8 = 0x18;
+    pub const RET: u8 = 0x19;
+    pub const LDAI: u8 = 0x1A;
+    pub const STAI: u8 = 0x1B;
+    pub const SETAI: u8 = 0x1C;
+    pub const STRI: u8 = 0x1D;
+    pub const LOADI: u8 = 0x1E;
+    pub const LDAX: u8 = 0x1F;
+    pub const STAX: u8 = 0x20;
+    pub const SETAX: u8 = 0x21;
+    pub const STRX: u8 = 0x22;
+    pub const LOADX: u8 = 0x23;
+    pub const AND: u8 = 0x24;
+    pub const OR: u8 = 0x25;
+    pub const XOR: u8 = 0x26;
+    pub const NOT: u8 = 0x27;
+    pub const SHL: u8 = 0x28;
+    pub const SHR: u8 = 0x29;
+    pub const ROL: u8 = 0x2A;
+    pub const ROR: u8 = 0x2B;
+    pub const JNO: u8 = 0x2C;
+    pub const JNC: u8 = 0x2D;
+    pub const JN: u8 = 0x2E;
+    pub const JNN: u8 = 0x2F;
+    pub const JLT: u8 = 0x30;
+    pub const JGE: u8 = 0x31;
+    pub const JGT: u8 = 0x32;
+    pub const JLE: u8 = 0x33;
+    pub const CMP: u8 = 0x34;
+    pub const MUL: u8 = 0x35;
+    pub const DIV: u8 = 0x36;
+    pub const MOD: u8 = 0x37;
+    pub const EI: u8 = 0x38;
+    pub const DI: u8 = 0x39;
+    pub const IRET: u8 = 0x3A;
+    pub const MOV: u8 = 0x3B;
+    pub const MOVI: u8 = 0x3C;
+    pub const ADDR: u8 = 0x3D;
+    pub const SUBR: u8 = 0x3E;
+    pub const CMPR: u8 = 0x3F;
+    pub const ANDR: u8 = 0x40;
+    pub const ORR: u8 = 0x41;
+    pub const XORR: u8 = 0x42;
+    pub const INCR: u8 = 0x43;
+    pub const DECR: u8 = 0x44;
+    pub const JCS: u8 = 0x45;
 
     #[test]
     fn test_assembler_output() {
         let expected = vec![
             // init:
-            SETV, 0, STR, 0x40, // x = 0
-            STR, 0x42, // z = 0
-            SETV, 1, STR, 0x41, // y = 1
+            SETV, 0, STR, 0x40, 0, // x = 0
+            STR, 0x42, 0, // z = 0
+            SETV, 1, STR, 0x41, 0, // y = 1
             // loop
 
             // print z
-            LDA, 0x42, OUT, // z = x + y
-            LDA, 0x40, // load x into acc
-            LOAD, 0x41, // load y into usr
-            ADD,  // add y to acc (x) -> acc = x + y
-            JO, 31, // exit if overflow
-            STA, 0x42, // store acc in z
+            LDA, 0x42, 0, OUT, // z = x + y
+            LDA, 0x40, 0, // load x into acc
+            LOAD, 0x41, 0,   // load y into usr
+            ADD, // add y to acc (x) -> acc = x + y
+            JO, 44, 0, // exit if overflow
+            STA, 0x42, 0, // store acc in z
             // x = y
-            LDA, 0x41, // load y into acc
-            STA, 0x40, // store y in x
+            LDA, 0x41, 0, // load y into acc
+            STA, 0x40, 0, // store y in x
             // y = z
-            LDA, 0x42, // load z into acc
-            STA, 0x41, // store z in y
+            LDA, 0x42, 0, // load z into acc
+            STA, 0x41, 0, // store z in y
             // while z < 255
-            JMP, 9, // reenter the loop otherwise
+            JMP, 12, 0, // reenter the loop otherwise
             // exit_good:
             EXIT, 1,
         ];
@@ -60,4 +116,774 @@ mod tests {
 
         assert_eq!(expected, actual);
     }
+
+    /// Assert that each of `sources` fails to assemble, with an error `expected` accepts
+    fn assert_rejected(sources: &[&str], expected: fn(&error::AssemblerError) -> bool) {
+        for source in sources {
+            match assembler::Assembler::assemble(source) {
+                Err(e) => assert!(expected(&e), "{:?}: {:?}", source, e),
+                Ok(output) => panic!("{:?} assembled to {:?}", source, output),
+            }
+        }
+    }
+
+    #[test]
+    fn test_disassembler_round_trip() {
+        let file_handle = File::open("./tests/fib.as").unwrap();
+        let mut assembler = assembler::Assembler::new(file_handle);
+        assembler.parse().unwrap();
+        let original = assembler.get_output();
+
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("JO label_45"));
+        assert!(source.contains("JMP label_13"));
+
+        let reassembled = assemble_str(&source);
+        assert_eq!(original, reassembled);
+    }
+
+    #[test]
+    fn test_disassembler_stack_ops() {
+        let original = vec![CALL, 6, 0, POPU, EXIT, 0, PUSHA, POPA, RET];
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("CALL label_7"));
+        assert!(source.contains("PUSH A"));
+        assert!(source.contains("POP U"));
+
+        let reassembled = assemble_str(&source);
+        assert_eq!(original, reassembled);
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let original = assemble_str(
+            "
+    LDA [0x40]
+    STA [ 64 ]
+    SETA [0x40]
+    STR [0b1000000]
+    LOAD [0x40]
+    LDA 0x40,U
+    STA 64, u
+    SETA 0x40,U
+    STR 0x40 , U
+    LOAD 0x40,U
+",
+        );
+        assert_eq!(
+            original,
+            vec![
+                LDAI, 0x40, 0, STAI, 0x40, 0, SETAI, 0x40, 0, STRI, 0x40, 0, LOADI, 0x40, 0, LDAX,
+                0x40, 0, STAX, 0x40, 0, SETAX, 0x40, 0, STRX, 0x40, 0, LOADX, 0x40, 0
+            ]
+        );
+
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("LDA [64]"));
+        assert!(source.contains("STR 64,U"));
+        assert_eq!(assemble_str(&source), original);
+
+        // Labels work in every addressing mode
+        let labelled = assemble_str("start:\n    LDA [start]\n    STA start,U\n");
+        assert_eq!(labelled, vec![LDAI, 0, 0, STAX, 0, 0]);
+
+        let mut assembler = assembler::Assembler::from_source("    JMP [0x40]\n");
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::InstructionError(..))
+        ));
+    }
+
+    #[test]
+    fn test_alu_mnemonics() {
+        let source = "    AND\n    OR\n    XOR\n    NOT\n    SHL\n    SHR\n    ROL\n    ROR\n";
+        let original = assemble_str(source);
+        assert_eq!(original, vec![AND, OR, XOR, NOT, SHL, SHR, ROL, ROR]);
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert_eq!(assemble_str(&source), original);
+
+        let original = assemble_str("    MUL\n    DIV\n    MOD\n    EI\n    DI\n    IRET\n");
+        assert_eq!(original, vec![MUL, DIV, MOD, EI, DI, IRET]);
+
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert_eq!(assemble_str(&source), original);
+    }
+
+    #[test]
+    fn test_register_operands() {
+        let source = "
+    MOV R1, R2
+    mov r7, a
+    MOV U, 0xFF
+    ADD R0, R1
+    SUB A, U
+    CMP R3, R4
+    AND R5, R6
+    OR R7, R0
+    XOR A, A
+    INC R2
+    DEC U
+    ADD
+    CMP
+    INC
+";
+        let original = assemble_str(source);
+        assert_eq!(
+            original,
+            vec![
+                MOV, 0x12, MOV, 0x78, MOVI, 0x09, 0xFF, ADDR, 0x01, SUBR, 0x89, CMPR, 0x34, ANDR,
+                0x56, ORR, 0x70, XORR, 0x88, INCR, 0x02, DECR, 0x09, ADD, CMP, INC,
+            ]
+        );
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("MOV R1, R2"));
+        assert!(source.contains("MOV U, 255"));
+        assert!(source.contains("INC R2"));
+        assert_eq!(assemble_str(&source), original);
+
+        assert_rejected(
+            &[
+                "    MOV R8, R0\n",
+                "    MOV R0, 256\n",
+                "    ADD R0, 5\n",
+                "    ADD R0\n",
+                "    INC R0, R1\n",
+                "    MOV 0x40, A\n",
+            ],
+            |e| matches!(e, error::AssemblerError::InstructionError(..)),
+        );
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        let original = assemble_str(
+            "
+    NOP
+top:
+    CMP
+    JNZ top
+    JNO top
+    JNC top
+    JN top
+    JNN top
+    JLT top
+    JGE top
+    JGT top
+    JLE top
+    JCS top
+",
+        );
+        assert_eq!(
+            original,
+            vec![
+                NOP, CMP, JNZ, 0, 0, JNO, 0, 0, JNC, 0, 0, JN, 0, 0, JNN, 0, 0, JLT, 0, 0, JGE, 0,
+                0, JGT, 0, 0, JLE, 0, 0, JCS, 0, 0
+            ]
+        );
+
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("JLT label_1"));
+        assert_eq!(assemble_str(&source), original);
+    }
+
+    #[test]
+    fn test_disassembler_odd_targets() {
+        let original = vec![
+            JZ, 0, 0, // resumes at 1, mid-instruction, but a label at 0 also resolves to 0
+            JC, 3, 0, // resumes at 4, mid-instruction, no label possible
+            JMP, 8, 0, // resumes at 9, the end of the image
+        ];
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.starts_with("label_0:"));
+        assert!(source.contains("JC 3"));
+        assert!(source.trim_end().ends_with("label_9:"));
+
+        let reassembled = assemble_str(&source);
+        assert_eq!(original, reassembled);
+    }
+
+    #[test]
+    fn test_disassembler_errors() {
+        use error::DisassemblerError;
+
+        let result = disassembler::Disassembler::new(vec![INC, 0xEE]).decode();
+        assert_eq!(result, Err(DisassemblerError::UnknownOpcode(1, 0xEE)));
+        let result = disassembler::Disassembler::new(vec![INC, LDA, 0x40]).decode();
+        assert_eq!(result, Err(DisassemblerError::TruncatedInstruction(1, LDA)));
+    }
+
+    #[test]
+    fn test_disassembler_data() {
+        let original = assemble_str(
+            "
+    lda msg
+    out
+    jmp done
+msg:
+    .byte 0xEE, 0x2A
+done:
+    exit 0
+    .byte 0x01, 0x40
+",
+        );
+        let source = disassembler::Disassembler::new(original.clone())
+            .disassemble()
+            .unwrap();
+        assert!(source.contains(".byte 0xEE"));
+        assert!(source.contains("JMP label_9"));
+        assert!(source.contains(".byte 0x01"));
+        assert!(source.trim_end().ends_with(".byte 0x40      ;; 0x000C"));
+        assert_eq!(assemble_str(&source), original);
+    }
+
+    #[test]
+    fn test_jit_at_offset_preserves_data() {
+        use crate::{cpu::Cpu, error::CpuError};
+
+        let mut cpu = Cpu::new();
+        cpu.memory.set(0x40, 41).unwrap();
+        cpu.memory.set(0x01, 0xAA).unwrap();
+
+        let source = "
+start:
+    lda 0x40
+    inc
+    sta 0x41
+    jz done ;; never taken, acc is 42
+    jmp done
+    exit 2
+done:
+    exit 7
+";
+        let result = assembler::Assembler::from_source(source)
+            .with_origin(0x20)
+            .jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(CpuError::Exit(7)))
+        ));
+        assert_eq!(cpu.memory.get(0x41), Ok(42));
+        assert_eq!(cpu.memory.get(0x40), Ok(41));
+        assert_eq!(cpu.memory.get(0x01), Ok(0xAA));
+        assert_eq!(cpu.memory.get(0x20), Ok(LDA));
+
+        // Same for an assembler that was already parsed, with ROM mapped
+        let mut assembler = assembler::Assembler::from_source(source).with_origin(0x100);
+        assembler.parse().unwrap();
+        let mut cpu = Cpu::builder().rom(0x40, &[41]).build().unwrap();
+        assert_eq!(assembler.load_and_run(&mut cpu), Err(CpuError::Exit(7)));
+        assert_eq!(cpu.memory.get(0x41), Ok(42));
+        assert_eq!(cpu.memory.set(0x40, 0), Err(CpuError::ReadOnly(0x40)));
+        assert_eq!(assembler.run(), Err(CpuError::Exit(7)));
+    }
+
+    #[test]
+    fn test_jit_skips_org_gaps() {
+        use crate::{cpu::Cpu, error::CpuError};
+
+        let mut cpu = Cpu::new();
+        cpu.memory.set(0x10, 0xAA).unwrap();
+
+        let source = "
+    jmp start
+.org 0x20
+start:
+    lda 0x10
+    sta 0x30
+    exit 3
+";
+        let result = assembler::Assembler::from_source(source).jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(CpuError::Exit(3)))
+        ));
+        assert_eq!(cpu.memory.get(0x10), Ok(0xAA));
+        assert_eq!(cpu.memory.get(0x30), Ok(0xAA));
+    }
+
+    #[test]
+    fn test_jit_file() {
+        use crate::{cpu::Cpu, error::CpuError};
+
+        let mut cpu = Cpu::new();
+        let result = assembler::Assembler::jit(File::open("./tests/fib.as").unwrap(), &mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(CpuError::Exit(1)))
+        ));
+        // x and y hold the last two terms before z overflowed
+        assert_eq!(cpu.memory.get(0x40), Ok(144));
+        assert_eq!(cpu.memory.get(0x41), Ok(233));
+    }
+
+    #[test]
+    fn test_program_too_large() {
+        let source = "    inc\n".repeat(20);
+        let mut assembler = assembler::Assembler::from_source(&source).with_origin(0xFFF0);
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::ProgramTooLarge(0x10004))
+        ));
+    }
+
+    #[test]
+    fn test_wide_labels() {
+        // Labels anywhere in the 64 KiB address space, referenced before and after
+        let source = "
+    JMP far
+back:
+    EXIT 2
+far:
+    LDA 0x1234
+    JNZ back
+";
+        let assembler = assembler::Assembler::from_source(source).with_origin(0x3FF0);
+        let mut cpu = crate::cpu::Cpu::builder()
+            .load(0x1234, &[1])
+            .build()
+            .unwrap();
+        let result = assembler.jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(crate::error::CpuError::Exit(2)))
+        ));
+
+        let output = assemble_str("    NOP\nlabel:\n    CALL label\n    JMP 0xBEEF\n");
+        assert_eq!(output, vec![NOP, CALL, 0, 0, JMP, 0xEF, 0xBE]);
+
+        // SETV and EXIT still take a byte
+        assert_rejected(&["    SETV 256\n", "    EXIT 0x100\n"], |e| {
+            matches!(e, error::AssemblerError::InstructionError(..))
+        });
+        let mut assembler = assembler::Assembler::from_source(".org 0x200\nx:\n    SETV x\n");
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::ExpressionError(_, 2))
+        ));
+        let mut assembler = assembler::Assembler::from_source("    LDA 0x10000\n");
+        assert!(assembler.parse().is_err());
+
+        // A label can only be defined once, even when nothing separates the two
+        assert_rejected(
+            &[
+                "x:\n    NOP\nx:\n    NOP\n",
+                "x:\nx:\n    NOP\n",
+                "    .var x\nx:\n    NOP\n",
+            ],
+            |e| matches!(e, error::AssemblerError::DuplicateLabel(label, _) if label == "x"),
+        );
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = r#"
+    LDA msg,U
+    JMP table
+.org 0x10
+msg:
+    .string "hi\n" ;; with a terminating 0
+    .byte 1, 0x02, 0b11
+table:
+    .word 0x1234, msg, end
+    .fill 3, 0xAA
+    .fill 2
+    .org 0x30
+end:
+    EXIT 0
+"#;
+        let output = assemble_str(source);
+        let mut expected = vec![LDAX, 0x10, 0, JMP, 0x17, 0];
+        expected.resize(0x10, 0);
+        expected.extend(b"hi\n\0");
+        expected.extend([1, 2, 3]);
+        expected.extend([0x34, 0x12, 0x10, 0, 0x2F, 0]);
+        expected.extend([0xAA, 0xAA, 0xAA, 0, 0]);
+        expected.resize(0x30, 0);
+        expected.extend([EXIT, 0]);
+        assert_eq!(output, expected);
+
+        // .org is an absolute address, not an offset from the origin
+        let mut assembler = assembler::Assembler::from_source("    NOP\n.org 0x104\n    .byte 7\n")
+            .with_origin(0x100);
+        assert_eq!(assembler.parse().unwrap(), 5);
+        assert_eq!(assembler.get_output(), vec![NOP, 0, 0, 0, 7]);
+
+        let mut assembler =
+            assembler::Assembler::from_source("    NOP\n    NOP\n.org 1\n    .byte 1\n");
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::Overlap(1, 3))
+        ));
+        let mut assembler = assembler::Assembler::from_source(".org 0xFFFF\n    .word 1\n");
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::ProgramTooLarge(0x10001))
+        ));
+
+        assert_rejected(
+            &[
+                "    .fill\n",
+                "    .string hi\n",
+                "    .string \"\\q\"\n",
+                "    .bogus 1\n",
+                "    .string \";; not a comment\n",
+            ],
+            |e| matches!(e, error::AssemblerError::DirectiveError(..)),
+        );
+        let mut assembler = assembler::Assembler::from_source("    NOP\n.org 0\n").with_origin(1);
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::DirectiveError(..))
+        ));
+    }
+
+    #[test]
+    fn test_variables() {
+        // Allocated after the output, in declaration order, and usable before their declaration
+        let source = "
+    LDA count
+    STA buffer,U
+    .var count
+    .var buffer 4
+    .var last
+    STA last
+";
+        let output = assemble_str(source);
+        assert_eq!(output, vec![LDA, 9, 0, STAX, 10, 0, STA, 14, 0]);
+
+        let source = "
+    .data 0x100
+    .var x 2
+    .var y
+    LDA y
+    EXIT 0
+";
+        let assembler = assembler::Assembler::from_source(source).with_origin(0xFA);
+        let mut cpu = crate::cpu::Cpu::builder()
+            .load(0x102, &[7])
+            .build()
+            .unwrap();
+        let result = assembler.jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(crate::error::CpuError::Exit(0)))
+        ));
+        assert_eq!(cpu.accumulator, 7);
+
+        let mut assembler = assembler::Assembler::from_source(
+            "    .data 2\n    .var x 2\n    NOP\n    NOP\n    NOP\n",
+        );
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::DataOverlap(ref name, 2, 1)) if name == "x"
+        ));
+        // Past the output is fine, even past its origin
+        let mut assembler =
+            assembler::Assembler::from_source("    .data 0\n    .var x 2\n    NOP\n")
+                .with_origin(2);
+        assert!(assembler.parse().is_ok());
+
+        assert_rejected(
+            &[
+                "    .var x\n    .var x\n",
+                "x:\n    NOP\n    .var x\n",
+                "    .var 0x40\n",
+                "    .var x 0\n",
+                "    .var x 2 3\n",
+            ],
+            |e| matches!(e, error::AssemblerError::DirectiveError(..)),
+        );
+    }
+
+    fn eval_expression(source: &str) -> Result<i64, expr::EvalError> {
+        let lookup = |name: &str| match name {
+            "label" => Some(0x1234),
+            "N" => Some(0x90),
+            _ => None,
+        };
+        expr::Expr::parse(source).unwrap().eval(&lookup)
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(eval_expression("label+2"), Ok(0x1236));
+        assert_eq!(eval_expression("(N*2)&0xFF"), Ok(0x20));
+        assert_eq!(eval_expression("N * 2 & 0xFF"), Ok(0x20));
+        assert_eq!(eval_expression("1 + 2 * 3 - 4"), Ok(3));
+        assert_eq!(eval_expression("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval_expression("<label"), Ok(0x34));
+        assert_eq!(eval_expression(">label"), Ok(0x12));
+        assert_eq!(eval_expression(">label+1"), Ok(0x13));
+        assert_eq!(eval_expression("1 << 4 | 0b1"), Ok(0x11));
+        assert_eq!(eval_expression("label >> 8"), Ok(0x12));
+        assert_eq!(eval_expression("-1"), Ok(-1));
+        assert_eq!(eval_expression("~0 ^ -1"), Ok(0));
+        assert_eq!(eval_expression("17 % 5 / 2"), Ok(1));
+        assert_eq!(
+            eval_expression("missing+1"),
+            Err(expr::EvalError::Undefined("missing".to_owned()))
+        );
+        assert!(matches!(
+            eval_expression("1/0"),
+            Err(expr::EvalError::Invalid(_))
+        ));
+
+        for source in ["", "1 +", "(1", "1)", "1 $ 2", "0xZZ", "* 2", "1 2"] {
+            assert!(expr::Expr::parse(source).is_err(), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "
+    .equ BASE 0x40
+    .equ N BASE / 8
+    LDA BASE+0x10
+    MOV R0, N*2
+    SETV <label
+    EXIT >label-1
+.org 0x1234
+label:
+    .byte >label, (N*2)&0xFF, -1
+    .word label+2, end-label
+end:
+";
+        let output = assemble_str(source);
+        assert_eq!(
+            &output[..11],
+            &[LDA, 0x50, 0, MOVI, 0, 16, SETV, 0x34, EXIT, 0x11, 0]
+        );
+        assert_eq!(&output[0x1234..], &[0x12, 0x10, 0xFF, 0x36, 0x12, 6, 0]);
+
+        assert_rejected(
+            &[
+                "    .byte 300\n",
+                "    .byte -129\n",
+                "    SETV 256-0\n",
+                "    .word later*0x10000\nlater:\n    NOP\n",
+                "    .fill -1\n",
+                "    .equ X later\nlater:\n",
+                "    .byte 1/0\n",
+                "    .byte 1 +\n",
+            ],
+            |e| matches!(e, error::AssemblerError::ExpressionError(..)),
+        );
+        assert_rejected(
+            &[
+                "    .equ X 1\n    .equ X 2\n",
+                "    .equ X 1\nX:\n    NOP\n",
+                "    .equ 0x40 1\n",
+                "    .equ X\n",
+            ],
+            |e| matches!(e, error::AssemblerError::DirectiveError(..)),
+        );
+        let mut assembler = assembler::Assembler::from_source("    .byte missing+1\n");
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::UndefinedLabel(ref label, 0)) if label == "missing"
+        ));
+    }
+
+    #[test]
+    fn test_macros() {
+        // Every expansion gets its own copy of the labels in the body
+        let source = "
+.macro countdown reg, n
+    MOV reg, n
+loop:
+    DEC reg
+    JNZ loop
+.endm
+    countdown R0, 3
+    countdown R1, 2
+    EXIT 0
+";
+        let output = assemble_str(source);
+        assert_eq!(
+            output,
+            vec![MOVI, 0, 3, DECR, 0, JNZ, 2, 0, MOVI, 1, 2, DECR, 1, JNZ, 10, 0, EXIT, 0]
+        );
+
+        let source = "
+.macro swap x, y
+    LDA x
+    LOAD y
+    STA y
+    STR x
+.endm
+    swap 0x40, cell+1
+    EXIT 0
+    .equ cell 0x40
+";
+        let assembler = assembler::Assembler::from_source(source);
+        let mut cpu = crate::cpu::Cpu::builder()
+            .load(0x40, &[1, 2])
+            .build()
+            .unwrap();
+        let result = assembler.jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(crate::error::CpuError::Exit(0)))
+        ));
+        assert_eq!(cpu.memory.get(0x40), Ok(2));
+        assert_eq!(cpu.memory.get(0x41), Ok(1));
+
+        // Commas inside a string argument don't split it
+        let source = ".macro text s, n\n    .string s\n    .byte n\n.endm\n    text \"a, b\", 7\n";
+        assert_eq!(assemble_str(source), b"a, b\x00\x07".to_vec());
+
+        // Errors point at the line of the body and at the call
+        let source = ".macro m\n    BOGUS\n.endm\n    NOP\n    m\n";
+        let error = assembler::Assembler::assemble(source).unwrap_err();
+        assert!(matches!(
+            error,
+            error::AssemblerError::InMacro(ref e, ref name, None, 1, 4)
+                if name == "m" && matches!(**e, error::AssemblerError::InstructionError(..))
+        ));
+        assert!(error
+            .to_string()
+            .contains("in macro m at line 2, called on line 5"));
+        let source =
+            ".macro inner\n    .byte 300\n.endm\n.macro outer\n    inner\n.endm\n    outer\n";
+        assert!(matches!(
+            assembler::Assembler::assemble(source),
+            Err(error::AssemblerError::InMacro(ref e, _, _, 4, 6))
+                if matches!(**e, error::AssemblerError::InMacro(_, _, _, 1, 4))
+        ));
+        // Including operands only known at the end
+        let source = ".macro m\n    .word later+0x10000\n.endm\n    m\nlater:\n    NOP\n";
+        assert!(matches!(
+            assembler::Assembler::assemble(source),
+            Err(error::AssemblerError::InMacro(ref e, _, _, 1, 3))
+                if matches!(**e, error::AssemblerError::ExpressionError(_, 1))
+        ));
+        let source = ".macro r\n    r\n.endm\n    r\n";
+        assert!(matches!(
+            assembler::Assembler::assemble(source),
+            Err(error::AssemblerError::InMacro(..))
+        ));
+
+        assert_rejected(
+            &[
+                ".macro m\n    NOP\n",
+                ".endm\n",
+                ".macro m\n.macro n\n.endm\n",
+                ".macro m x\n.endm\n    m\n",
+                ".macro m\n.endm\n.macro m\n.endm\n",
+                ".macro .m\n.endm\n",
+                ".macro m 1\n.endm\n",
+                ".macro ld base, U\n    LDA base,U\n.endm\n",
+                ".macro m r0\n.endm\n",
+                ".macro lda\n.endm\n",
+                ".macro Push x\n.endm\n",
+                ".macro mov\n.endm\n",
+            ],
+            |e| matches!(e, error::AssemblerError::DirectiveError(..)),
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        // Paths are relative to the file with the .include, and macros and constants carry over
+        let assembler = assembler::Assembler::open("./tests/include/main.as").unwrap();
+        let mut cpu = crate::cpu::Cpu::builder()
+            .load(0x40, &[1, 2])
+            .build()
+            .unwrap();
+        let result = assembler.jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(crate::error::CpuError::Exit(0)))
+        ));
+        assert_eq!(cpu.memory.get(0x40), Ok(2));
+        assert_eq!(cpu.memory.get(0x41), Ok(1));
+
+        // Errors give the line in the included file, and the line it was included on
+        let mut assembler = assembler::Assembler::open("./tests/include/broken.as").unwrap();
+        let error = assembler.parse().unwrap_err();
+        assert!(matches!(
+            error,
+            error::AssemblerError::Included(ref e, ref path, 2)
+                if path.ends_with("lib/bad.as")
+                    && matches!(**e, error::AssemblerError::InstructionError(_, 1))
+        ));
+        assert!(error.to_string().ends_with("(included on line 3)"));
+        // A file two others include is only assembled the first time
+        let assembler = assembler::Assembler::open("./tests/include/diamond.as").unwrap();
+        let mut cpu = crate::cpu::Cpu::builder()
+            .load(0x40, &[1, 2])
+            .build()
+            .unwrap();
+        let result = assembler.jit_into(&mut cpu);
+        assert!(matches!(
+            result,
+            Err(error::JitError::Cpu(crate::error::CpuError::Exit(0)))
+        ));
+        assert_eq!(cpu.memory.get(0x41), Ok(1));
+        assert_eq!(cpu.memory.get(0x42), Ok(2));
+
+        let mut assembler = assembler::Assembler::open("./tests/include/cycle_a.as").unwrap();
+        assert!(matches!(
+            assembler.parse(),
+            Err(error::AssemblerError::Included(ref e, _, 0))
+                if matches!(**e, error::AssemblerError::DirectiveError(_, 0))
+        ));
+        assert!(matches!(
+            assembler::Assembler::assemble("    .include \"missing.as\"\n"),
+            Err(error::AssemblerError::Included(ref e, _, 0))
+                if matches!(**e, error::AssemblerError::IOError(_))
+        ));
+        assert!(matches!(
+            assembler::Assembler::assemble("    .include missing.as\n"),
+            Err(error::AssemblerError::DirectiveError(_, 0))
+        ));
+
+        // A macro from another file names it, even when the error only shows up at the end
+        let source = "    .include \"tests/include/lib/swap.as\"\n    swap missing, CELL\n";
+        let error = assembler::Assembler::assemble(source).unwrap_err();
+        assert!(matches!(
+            error,
+            error::AssemblerError::InMacro(ref e, _, Some(ref path), 3, 1)
+                if path.ends_with("lib/swap.as")
+                    && matches!(**e, error::AssemblerError::UndefinedLabel(_, 3))
+        ));
+        assert!(error
+            .to_string()
+            .contains("in macro swap at tests/include/lib/swap.as line 4, called on line 2"));
+    }
+
+    #[test]
+    fn test_disassembler_compat_mode() {
+        use crate::cpu::AddressMode;
+
+        // An 8-bit image, one byte addresses
+        let original = vec![NOP, LDA, 0x40, JNZ, 0, EXIT, 1];
+        let source = disassembler::Disassembler::new(original)
+            .with_mode(AddressMode::Narrow)
+            .disassemble()
+            .unwrap();
+        assert!(source.contains("LDA 64"));
+        assert!(source.contains("JNZ label_1"));
+        // Reassembling it migrates the program to the wide encoding
+        assert_eq!(
+            assemble_str(&source),
+            vec![NOP, LDA, 0x40, 0, JNZ, 0, 0, EXIT, 1]
+        );
+    }
 }
@@ -1,45 +1,189 @@
 use {
     crate::{
-        error::CpuError, flags::Flags, instruction::Instruction, memory::Memory, DEBUG, MEMORY_SIZE,
+        error::CpuError,
+        flags::Flags,
+        instruction::{Instruction, Register},
+        interrupt::IrqLines,
+        io::{Input, Output, Stdin, Stdout},
+        memory::{Device, Memory},
+        trace::{Change, TraceRecord, Tracer},
+        IRQ_LINES, MEMORY_SIZE, NARROW_MEMORY_SIZE, NARROW_STACK_SIZE, REGISTERS, STACK_SIZE,
+    },
+    std::{
+        fs::File,
+        io::Read,
+        ops::RangeInclusive,
+        path::Path,
+        time::{Duration, Instant},
     },
-    std::{fs::File, io::Read, path::Path},
 };
 
+/// How often, in steps, a run with a timeout checks the clock
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+/// Why a bounded run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// The program executed `EXIT`
+    Exit(u8),
+    /// The program faulted
+    Fault(CpuError),
+    /// The step budget ran out before the program stopped
+    BudgetExhausted,
+    /// The wall-clock limit passed before the program stopped
+    TimedOut,
+}
+
+/// The result of a bounded run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunReport {
+    pub stop: Stop,
+    /// Instructions executed, including the one that exited or faulted
+    pub steps: u64,
+}
+
+/// Bounds for `Cpu::run_with`, unlimited by default
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+/// How wide addresses are. `Wide` is the native 16-bit machine; `Narrow` runs binaries built
+/// for the original 8-bit one, with one byte address operands, pointers and return addresses,
+/// and its 255 byte memory map.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AddressMode {
+    #[default]
+    Wide,
+    Narrow,
+}
+impl AddressMode {
+    /// Bytes in an address operand, pointer or return address
+    pub fn address_size(self) -> usize {
+        match self {
+            AddressMode::Wide => 2,
+            AddressMode::Narrow => 1,
+        }
+    }
+
+    /// Bytes of memory the program can address
+    pub fn memory_size(self) -> usize {
+        match self {
+            AddressMode::Wide => MEMORY_SIZE,
+            AddressMode::Narrow => NARROW_MEMORY_SIZE,
+        }
+    }
+
+    pub fn stack_size(self) -> usize {
+        match self {
+            AddressMode::Wide => STACK_SIZE,
+            AddressMode::Narrow => NARROW_STACK_SIZE,
+        }
+    }
+
+    /// Where `sp` starts, the last byte of memory
+    pub fn stack_top(self) -> u16 {
+        (self.memory_size() - 1) as u16
+    }
+
+    /// The start of the interrupt vector table, directly below the stack
+    pub fn vector_table(self) -> usize {
+        self.memory_size() - self.stack_size() - IRQ_LINES * self.address_size()
+    }
+}
+
+/// A snapshot of the registers, for diffing in traces
+struct Snapshot {
+    registers: [u8; REGISTERS],
+    accumulator: u8,
+    user: u8,
+    sp: u16,
+    zero: bool,
+    overflow: bool,
+    carry: bool,
+    negative: bool,
+}
+
 pub struct Cpu {
-    pub ip: u8,
+    pub ip: u16,
+    /// The next free stack slot; the stack grows down from the top of memory
+    pub sp: u16,
+    pub mode: AddressMode,
     pub memory: Memory,
     pub flags: Flags,
     pub user: u8,
     pub accumulator: u8,
+    /// The general-purpose registers R0 to R7
+    pub registers: [u8; REGISTERS],
+    pub output: Box<dyn Output + Send>,
+    pub input: Box<dyn Input + Send>,
+    pub tracer: Option<Box<dyn Tracer + Send>>,
+    pub irq: IrqLines,
 }
 impl Cpu {
     pub fn new() -> Self {
         Self {
             ip: 0,
+            sp: AddressMode::Wide.stack_top(),
+            mode: AddressMode::Wide,
             memory: Memory::new(),
             flags: Flags::default(),
             user: 0,
             accumulator: 0,
+            registers: [0; REGISTERS],
+            output: Box::new(Stdout),
+            input: Box::new(Stdin),
+            tracer: None,
+            irq: IrqLines::new(),
         }
     }
 
+    /// Send the output of `OUT` to `output` instead of stdout
+    pub fn set_output<O: Output + Send + 'static>(&mut self, output: O) {
+        self.output = Box::new(output);
+    }
+
+    /// Report every executed instruction to `tracer`, replacing any previous one
+    pub fn set_tracer<T: Tracer + Send + 'static>(&mut self, tracer: T) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Stop tracing, handing back the tracer if there was one
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer + Send>> {
+        self.tracer.take()
+    }
+
+    /// Read the input of `IN` from `input` instead of stdin
+    pub fn set_input<I: Input + Send + 'static>(&mut self, input: I) {
+        self.input = Box::new(input);
+    }
+
+    /// A handle to this cpu's IRQ lines, for devices or other threads to raise interrupts
+    pub fn irq_lines(&self) -> IrqLines {
+        self.irq.clone()
+    }
+
+    /// Request an interrupt on `line`, see `IrqLines::raise`
+    pub fn raise_irq(&self, line: u8) {
+        self.irq.raise(line)
+    }
+
+    pub fn builder() -> CpuBuilder {
+        CpuBuilder::default()
+    }
+
     // pub fn from_file<P: AsRef<Path>>(path: P) -> Self {}
 
-    pub fn from_binary<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
-        let mut handle = File::open(path)?;
+    /// Load the binary at `path` at address 0, decoded with `mode`'s address width. Reading the
+    /// file fails with `InputError`, and an image too large for `mode` with `MemoryError`
+    pub fn from_binary<P: AsRef<Path>>(path: P, mode: AddressMode) -> Result<Self, CpuError> {
         let mut bytes = Vec::new();
-        handle.read_to_end(&mut bytes)?;
-
-        let memory = Memory::new_with_instructions(&bytes);
-        let flags = Flags::default();
+        File::open(path)
+            .and_then(|mut handle| handle.read_to_end(&mut bytes))
+            .map_err(|e| CpuError::InputError(e.kind()))?;
 
-        Ok(Cpu {
-            ip: 0,
-            memory,
-            flags,
-            user: 0,
-            accumulator: 0,
-        })
+        Cpu::builder().mode(mode).program(&bytes).build()
     }
 
     pub fn run(&mut self) -> Result<(), CpuError> {
@@ -48,52 +192,416 @@ impl Cpu {
         }
     }
 
+    /// Run for at most `max_steps` instructions
+    pub fn run_for(&mut self, max_steps: u64) -> RunReport {
+        self.run_with(Limits {
+            max_steps: Some(max_steps),
+            timeout: None,
+        })
+    }
+
+    /// Run for at most `timeout` of wall-clock time
+    pub fn run_for_duration(&mut self, timeout: Duration) -> RunReport {
+        self.run_with(Limits {
+            max_steps: None,
+            timeout: Some(timeout),
+        })
+    }
+
+    /// Run until the program exits or faults, or one of `limits` is hit
+    pub fn run_with(&mut self, limits: Limits) -> RunReport {
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        let mut steps = 0;
+        loop {
+            if limits.max_steps.is_some_and(|max_steps| steps >= max_steps) {
+                return RunReport {
+                    stop: Stop::BudgetExhausted,
+                    steps,
+                };
+            }
+            if let Some(deadline) = deadline {
+                if steps % CLOCK_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                    return RunReport {
+                        stop: Stop::TimedOut,
+                        steps,
+                    };
+                }
+            }
+
+            let result = self.step();
+            steps += 1;
+            match result {
+                Ok(()) => (),
+                Err(CpuError::Exit(code)) => {
+                    return RunReport {
+                        stop: Stop::Exit(code),
+                        steps,
+                    }
+                }
+                Err(e) => {
+                    return RunReport {
+                        stop: Stop::Fault(e),
+                        steps,
+                    }
+                }
+            }
+        }
+    }
+
     pub fn step(&mut self) -> Result<(), CpuError> {
-        if self.ip >= 0xFF || self.ip as usize >= MEMORY_SIZE {
+        if self.flags.interrupt_enable {
+            if let Some(line) = self.irq.take() {
+                self.interrupt(line)?;
+            }
+        }
+
+        if self.ip as usize >= self.mode.memory_size() {
             return Err(CpuError::AOverflow);
         }
 
-        let instruction = self.memory.get(self.ip)?;
-        let instruction = match Instruction::from_byte(instruction) {
-            Some(single_byte) => single_byte,
-            None => {
-                self.ip += 1;
-                let arg = self.memory.get(self.ip)?;
-                Instruction::from_byte_and_arg(instruction, arg)?
+        let start = self.ip;
+        let opcode = self.read(self.ip)?;
+        let size =
+            Instruction::operand_size(opcode, self.mode).ok_or(CpuError::MalformedInput(opcode))?;
+        let mut operand = [0; 2];
+        for byte in operand.iter_mut().take(size) {
+            // `ip` ends up on the last byte of the instruction
+            self.ip = self.ip.checked_add(1).ok_or(CpuError::AOverflow)?;
+            *byte = self.read(self.ip)?;
+        }
+        let instruction = Instruction::decode(opcode, &operand[..size])?;
+
+        let before = match self.tracer {
+            Some(_) => {
+                self.memory.start_journal();
+                Some(self.snapshot())
             }
+            None => None,
         };
 
-        if DEBUG {
-            eprintln!("({}) -> {:?}", String::from(instruction), self);
+        let result = instruction.execute(self);
+
+        if result.is_ok() {
+            self.ip = self.ip.wrapping_add(1);
+        }
+
+        if let Some(before) = before {
+            self.trace(start, instruction, before);
         }
 
-        instruction.execute(self)?;
+        result
+    }
+
+    /// Read the byte at `addr`, faulting if it is outside the memory of the current mode
+    pub fn read(&mut self, addr: u16) -> Result<u8, CpuError> {
+        self.check_address(addr)?;
+        self.memory.get(addr)
+    }
 
-        self.ip += 1;
+    /// Read the byte at `addr` without side effects on devices, see `Memory::peek`
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        self.check_address(addr).ok()?;
+        self.memory.peek(addr)
+    }
 
-        if DEBUG {
-            eprintln!("After: {:?}", self);
+    /// Write the byte at `addr`, faulting if it is outside the memory of the current mode
+    pub fn write(&mut self, addr: u16, value: u8) -> Result<(), CpuError> {
+        self.check_address(addr)?;
+        self.memory.set(addr, value)
+    }
+
+    fn check_address(&self, addr: u16) -> Result<(), CpuError> {
+        if addr as usize >= self.mode.memory_size() {
+            return Err(CpuError::MemoryError(addr as usize));
         }
+        Ok(())
+    }
 
-        if self.flags.clear {
-            self.flags.clear_flags();
-        } else if self.flags.any() {
-            self.flags.clear = true;
-        } else {
-            if self.accumulator == 0 || self.user == 0 {
-                self.flags.zero = true;
-            }
+    /// Read a little-endian address of the current mode's width starting at `addr`
+    pub fn read_address(&mut self, addr: u16) -> Result<u16, CpuError> {
+        let mut value = 0;
+        for offset in (0..self.mode.address_size()).rev() {
+            let byte_addr = addr
+                .checked_add(offset as u16)
+                .ok_or(CpuError::MemoryError(addr as usize + offset))?;
+            value = value << 8 | self.read(byte_addr)? as u16;
         }
+        Ok(value)
+    }
 
+    /// Enter the handler for `line` with interrupts disabled, saving `ip` and the flags for
+    /// `IRET`. Vector entries are jump targets, like the argument of `CALL`.
+    fn interrupt(&mut self, line: u8) -> Result<(), CpuError> {
+        let entry = self.mode.vector_table() + line as usize * self.mode.address_size();
+        let vector = self.read_address(entry as u16)?;
+        self.push_address(self.ip)?;
+        self.push(self.flags.to_byte())?;
+        self.flags.interrupt_enable = false;
+        self.ip = vector.wrapping_add(1);
         Ok(())
     }
+
+    /// Push `value` onto the stack
+    pub fn push(&mut self, value: u8) -> Result<(), CpuError> {
+        if (self.sp as usize) < self.mode.memory_size() - self.mode.stack_size() {
+            return Err(CpuError::StackOverflow);
+        }
+        self.write(self.sp, value)?;
+        self.sp -= 1;
+        Ok(())
+    }
+
+    /// Pop the most recently pushed value off the stack
+    pub fn pop(&mut self) -> Result<u8, CpuError> {
+        if self.sp >= self.mode.stack_top() {
+            return Err(CpuError::StackUnderflow);
+        }
+        self.sp += 1;
+        self.read(self.sp)
+    }
+
+    /// Push a return address, high byte first so it sits little-endian in memory
+    pub fn push_address(&mut self, addr: u16) -> Result<(), CpuError> {
+        let bytes = addr.to_le_bytes();
+        for byte in bytes[..self.mode.address_size()].iter().rev() {
+            self.push(*byte)?;
+        }
+        Ok(())
+    }
+
+    /// Pop an address pushed by `push_address`
+    pub fn pop_address(&mut self) -> Result<u16, CpuError> {
+        let mut bytes = [0; 2];
+        for byte in bytes[..self.mode.address_size()].iter_mut() {
+            *byte = self.pop()?;
+        }
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    /// The value of a register operand
+    pub fn register(&self, register: Register) -> u8 {
+        match register {
+            Register::R(register) => self.registers[register.index()],
+            Register::A => self.accumulator,
+            Register::U => self.user,
+        }
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u8) {
+        match register {
+            Register::R(register) => self.registers[register.index()] = value,
+            Register::A => self.accumulator = value,
+            Register::U => self.user = value,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            accumulator: self.accumulator,
+            user: self.user,
+            sp: self.sp,
+            zero: self.flags.zero,
+            overflow: self.flags.overflow,
+            carry: self.flags.carry,
+            negative: self.flags.negative,
+        }
+    }
+
+    fn trace(&mut self, ip: u16, instruction: Instruction, before: Snapshot) {
+        let after = self.snapshot();
+        let mut changes = Vec::new();
+        if before.accumulator != after.accumulator {
+            changes.push(Change::Accumulator(before.accumulator, after.accumulator));
+        }
+        if before.user != after.user {
+            changes.push(Change::User(before.user, after.user));
+        }
+        for (idx, (old, new)) in before.registers.iter().zip(after.registers).enumerate() {
+            if *old != new {
+                changes.push(Change::Register(idx as u8, *old, new));
+            }
+        }
+        if before.sp != after.sp {
+            changes.push(Change::StackPointer(before.sp, after.sp));
+        }
+        if before.zero != after.zero {
+            changes.push(Change::Zero(before.zero, after.zero));
+        }
+        if before.overflow != after.overflow {
+            changes.push(Change::Overflow(before.overflow, after.overflow));
+        }
+        if before.carry != after.carry {
+            changes.push(Change::Carry(before.carry, after.carry));
+        }
+        if before.negative != after.negative {
+            changes.push(Change::Negative(before.negative, after.negative));
+        }
+        changes.extend(self.memory.take_journal());
+
+        let record = TraceRecord {
+            ip,
+            instruction,
+            changes,
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(&record);
+        }
+    }
+}
+// A host may run the cpu on its own thread, as `IrqLines` expects
+const _: fn() = || {
+    fn _assert_send<T: Send>() {}
+    _assert_send::<Cpu>();
+};
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl std::fmt::Debug for Cpu {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Cpu {{\n\tip: {};\n\tflags: {:?};\n\tregisters: {{\n\t\tusr: {};\n\t\tacc: {}\n\t}}\n\tmemory: {:?}\n}};",
-            self.ip, self.flags, self.user, self.accumulator, self.memory,
+            "Cpu {{\n\tip: {};\n\tsp: {};\n\tmode: {:?};\n\tflags: {:?};\n\tregisters: {{\n\t\tusr: {};\n\t\tacc: {};\n\t\tgeneral: {:?}\n\t}}\n\tmemory: {:?}\n}};",
+            self.ip, self.sp, self.mode, self.flags, self.user, self.accumulator, self.registers, self.memory,
         )
     }
 }
+
+#[derive(Default)]
+pub struct CpuBuilder {
+    memory: Option<Memory>,
+    output: Option<Box<dyn Output + Send>>,
+    input: Option<Box<dyn Input + Send>>,
+    tracer: Option<Box<dyn Tracer + Send>>,
+    segments: Vec<(u16, Vec<u8>)>,
+    roms: Vec<RangeInclusive<u16>>,
+    devices: Vec<(RangeInclusive<u16>, Box<dyn Device + Send>)>,
+    ip: u16,
+    mode: AddressMode,
+    user: u8,
+    accumulator: u8,
+    registers: [u8; REGISTERS],
+}
+impl CpuBuilder {
+    /// Start from `memory` instead of zeroed memory
+    pub fn memory(mut self, memory: Memory) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Load `bytes` at address 0
+    pub fn program(self, bytes: &[u8]) -> Self {
+        self.load(0, bytes)
+    }
+
+    /// Load `bytes` at `offset`, on top of anything loaded before
+    pub fn load(mut self, offset: u16, bytes: &[u8]) -> Self {
+        self.segments.push((offset, bytes.to_vec()));
+        self
+    }
+
+    /// Load `bytes` at `offset` and make them read-only
+    pub fn rom(mut self, offset: u16, bytes: &[u8]) -> Self {
+        if !bytes.is_empty() {
+            // Past the end of memory is clamped here, `build` rejects it like any other load
+            let end = (offset as usize + bytes.len() - 1).min(u16::MAX as usize);
+            self.roms.push(offset..=end as u16);
+        }
+        self.load(offset, bytes)
+    }
+
+    /// Map `device` at `range`, on top of any ROM
+    pub fn device<D: Device + Send + 'static>(
+        mut self,
+        range: RangeInclusive<u16>,
+        device: D,
+    ) -> Self {
+        self.devices.push((range, Box::new(device)));
+        self
+    }
+
+    /// Send the output of `OUT` to `output`, stdout by default
+    pub fn output<O: Output + Send + 'static>(mut self, output: O) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Read the input of `IN` from `input`, stdin by default
+    pub fn input<I: Input + Send + 'static>(mut self, input: I) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Report every executed instruction to `tracer`
+    pub fn tracer<T: Tracer + Send + 'static>(mut self, tracer: T) -> Self {
+        self.tracer = Some(Box::new(tracer));
+        self
+    }
+
+    pub fn ip(mut self, ip: u16) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    /// Run with `mode`'s address width and memory map, `AddressMode::Wide` by default
+    pub fn mode(mut self, mode: AddressMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn user(mut self, user: u8) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn accumulator(mut self, accumulator: u8) -> Self {
+        self.accumulator = accumulator;
+        self
+    }
+
+    /// Start `register` at `value`
+    pub fn register(mut self, register: Register, value: u8) -> Self {
+        match register {
+            Register::R(register) => self.registers[register.index()] = value,
+            Register::A => self.accumulator = value,
+            Register::U => self.user = value,
+        }
+        self
+    }
+
+    /// Fails if a loaded segment runs past the end of the mode's memory
+    pub fn build(self) -> Result<Cpu, CpuError> {
+        let mut memory = self.memory.unwrap_or_default();
+        for (offset, bytes) in self.segments.iter() {
+            let end = *offset as usize + bytes.len();
+            if end > self.mode.memory_size() {
+                // The first address past the end of memory
+                return Err(CpuError::MemoryError(self.mode.memory_size()));
+            }
+            memory.load(*offset, bytes)?;
+        }
+        for range in self.roms {
+            memory.map_rom(range);
+        }
+        for (range, device) in self.devices {
+            memory.map_boxed_device(range, device);
+        }
+
+        Ok(Cpu {
+            ip: self.ip,
+            sp: self.mode.stack_top(),
+            mode: self.mode,
+            memory,
+            flags: Flags::default(),
+            user: self.user,
+            accumulator: self.accumulator,
+            registers: self.registers,
+            output: self.output.unwrap_or_else(|| Box::new(Stdout)),
+            input: self.input.unwrap_or_else(|| Box::new(Stdin)),
+            tracer: self.tracer,
+            irq: IrqLines::new(),
+        })
+    }
+}
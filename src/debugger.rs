@@ -0,0 +1,388 @@
+use crate::{
+    cpu::Cpu,
+    error::CpuError,
+    instruction::{Instruction, Register},
+};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    io::{self, BufRead, Write},
+};
+
+const HELP: &str = "\
+commands:
+    break <addr>          Set a breakpoint (alias: b)
+    delete <addr>         Remove a breakpoint (alias: d)
+    breakpoints           List breakpoints
+    continue              Run until a breakpoint or the program stops (alias: c)
+    step [n]              Execute one (or n) instructions (alias: s)
+    next                  Run until the instruction after the current one (alias: n)
+    regs                  Print ip, registers and flags (alias: r)
+    mem <addr> [len]      Dump memory, 16 bytes by default (alias: x)
+    set <reg> <value>     Set ip, sp, acc, usr, r0-r7 or a flag
+    poke <addr> <value>   Write a byte to memory
+    help                  Show this message (alias: h)
+    quit                  Leave the debugger (alias: q)";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Continue,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Running,
+    Exited(u8),
+    Faulted(CpuError),
+}
+
+pub struct Debugger {
+    pub cpu: Cpu,
+    breakpoints: BTreeSet<u16>,
+    state: State,
+}
+impl Debugger {
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            state: State::Running,
+        }
+    }
+
+    /// The exit code of the program, if it has run to an `EXIT`
+    pub fn exit_code(&self) -> Option<u8> {
+        match self.state {
+            State::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Read commands from `input` until it is exhausted or `quit` is entered
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        self.print_location(&mut output)?;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(dbg) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            if self.command(&line, &mut output)? == Control::Quit {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<Control> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match parts.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Control::Continue),
+        };
+
+        match (command, args) {
+            ("break" | "b", [addr]) => match parse_number(addr) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    writeln!(out, "breakpoint at 0x{:04X}", addr)?;
+                }
+                None => writeln!(out, "invalid address {:?}", addr)?,
+            },
+            ("delete" | "d", [addr]) => match parse_number(addr) {
+                Some(addr) if self.breakpoints.remove(&addr) => {
+                    writeln!(out, "removed breakpoint at 0x{:04X}", addr)?
+                }
+                _ => writeln!(out, "no breakpoint at {}", addr)?,
+            },
+            ("breakpoints", []) => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "no breakpoints")?;
+                }
+                for addr in self.breakpoints.iter() {
+                    writeln!(out, "0x{:04X}", addr)?;
+                }
+            }
+            ("continue" | "c", []) => {
+                if self.ensure_running(out)? {
+                    self.run_until(out, |_| false)?;
+                }
+            }
+            ("step" | "s", []) => self.step_n(1, out)?,
+            ("step" | "s", [n]) => match parse_number(n) {
+                Some(0) | None => writeln!(out, "invalid count {:?}", n)?,
+                Some(n) => self.step_n(n, out)?,
+            },
+            ("next" | "n", []) => {
+                if self.ensure_running(out)? {
+                    let after = self.cpu.ip as usize + self.current_len();
+                    self.run_until(out, |cpu| cpu.ip as usize == after)?;
+                }
+            }
+            ("regs" | "r", []) => self.print_registers(out)?,
+            ("mem" | "x", [addr]) => self.dump(addr, "16", out)?,
+            ("mem" | "x", [addr, len]) => self.dump(addr, len, out)?,
+            ("set", [register, value]) => self.set(register, value, out)?,
+            ("poke", [addr, value]) => match (parse_number(addr), parse_byte(value)) {
+                (Some(addr), Some(value)) => {
+                    if let Err(e) = self.cpu.write(addr, value) {
+                        writeln!(out, "{}", e)?;
+                    }
+                }
+                _ => writeln!(out, "usage: poke <addr> <value>")?,
+            },
+            ("help" | "h", []) => writeln!(out, "{}", HELP)?,
+            ("quit" | "q", []) => return Ok(Control::Quit),
+            _ => writeln!(out, "unknown command {:?}, try `help`", line.trim())?,
+        }
+        Ok(Control::Continue)
+    }
+
+    fn ensure_running<W: Write>(&self, out: &mut W) -> io::Result<bool> {
+        match self.state {
+            State::Running => Ok(true),
+            State::Exited(code) => {
+                writeln!(out, "program has exited with code {}", code)?;
+                Ok(false)
+            }
+            State::Faulted(e) => {
+                writeln!(out, "program has faulted: {}", e)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Execute `n` instructions, which must be at least one
+    fn step_n<W: Write>(&mut self, n: u16, out: &mut W) -> io::Result<()> {
+        if !self.ensure_running(out)? {
+            return Ok(());
+        }
+        let mut remaining = n;
+        self.run_until(out, |_| {
+            remaining = remaining.saturating_sub(1);
+            remaining == 0
+        })
+    }
+
+    /// Step until `stop` returns true, a breakpoint is hit, or the program stops
+    fn run_until<W: Write, F: FnMut(&Cpu) -> bool>(
+        &mut self,
+        out: &mut W,
+        mut stop: F,
+    ) -> io::Result<()> {
+        loop {
+            match self.cpu.step() {
+                Ok(()) => (),
+                Err(CpuError::Exit(code)) => {
+                    self.state = State::Exited(code);
+                    return writeln!(out, "program exited with code {}", code);
+                }
+                Err(e) => {
+                    self.state = State::Faulted(e);
+                    return writeln!(out, "fault at 0x{:04X}: {}", self.cpu.ip, e);
+                }
+            }
+
+            if stop(&self.cpu) {
+                return self.print_location(out);
+            }
+            if self.breakpoints.contains(&self.cpu.ip) {
+                write!(out, "breakpoint: ")?;
+                return self.print_location(out);
+            }
+        }
+    }
+
+    fn decode_current(&self) -> Option<Instruction> {
+        let byte = self.cpu.peek(self.cpu.ip)?;
+        let size = Instruction::operand_size(byte, self.cpu.mode)?;
+        let operand = (1..=size)
+            .map(|offset| self.cpu.peek(self.cpu.ip.checked_add(offset as u16)?))
+            .collect::<Option<Vec<u8>>>()?;
+        Instruction::decode(byte, &operand).ok()
+    }
+
+    fn current_len(&self) -> usize {
+        self.decode_current()
+            .map_or(1, |instruction| instruction.size(self.cpu.mode))
+    }
+
+    fn print_location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self.decode_current() {
+            Some(instruction) => writeln!(
+                out,
+                "0x{:04X}: {}",
+                self.cpu.ip,
+                String::from(instruction)
+            ),
+            None => writeln!(out, "0x{:04X}: ??", self.cpu.ip),
+        }
+    }
+
+    fn print_registers<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "ip=0x{:04X} sp=0x{:04X} acc={} (0x{:02X}) usr={} (0x{:02X}) zero={} overflow={} carry={} negative={} ie={}",
+            self.cpu.ip,
+            self.cpu.sp,
+            self.cpu.accumulator,
+            self.cpu.accumulator,
+            self.cpu.user,
+            self.cpu.user,
+            self.cpu.flags.zero as u8,
+            self.cpu.flags.overflow as u8,
+            self.cpu.flags.carry as u8,
+            self.cpu.flags.negative as u8,
+            self.cpu.flags.interrupt_enable as u8,
+        )?;
+        let general = self
+            .cpu
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| format!("r{}={}", idx, value))
+            .collect::<Vec<String>>();
+        writeln!(out, "{}", general.join(" "))
+    }
+
+    fn dump<W: Write>(&self, addr: &str, len: &str, out: &mut W) -> io::Result<()> {
+        let (start, len) = match (parse_number(addr), parse_number(len)) {
+            (Some(start), Some(len)) => (start as usize, len as usize),
+            _ => return writeln!(out, "usage: mem <addr> [len]"),
+        };
+        let end = (start + len).min(self.cpu.mode.memory_size());
+        for line_start in (start..end).step_by(8) {
+            let bytes = (line_start..(line_start + 8).min(end))
+                .map(|addr| match self.cpu.peek(addr as u16) {
+                    Some(byte) => format!("{:02X}", byte),
+                    None => "??".to_owned(),
+                })
+                .collect::<Vec<String>>()
+                .join(" ");
+            writeln!(out, "0x{:04X}: {}", line_start, bytes)?;
+        }
+        Ok(())
+    }
+
+    fn set<W: Write>(&mut self, register: &str, value: &str, out: &mut W) -> io::Result<()> {
+        let value = match parse_number(value) {
+            Some(value) => value,
+            None => return writeln!(out, "invalid value {:?}", value),
+        };
+        // ip and sp are addresses, everything else a byte
+        let byte = u8::try_from(value);
+        if let Some(general @ Register::R(_)) = Register::parse(register) {
+            match byte {
+                Ok(byte) => self.cpu.set_register(general, byte),
+                Err(_) => return writeln!(out, "{} doesn't fit in {}", value, register),
+            }
+            return Ok(());
+        }
+        match (register, byte) {
+            ("ip", _) => self.cpu.ip = value,
+            ("sp", _) => self.cpu.sp = value,
+            ("acc" | "accumulator", Ok(byte)) => self.cpu.accumulator = byte,
+            ("usr" | "user", Ok(byte)) => self.cpu.user = byte,
+            ("acc" | "accumulator" | "usr" | "user", Err(_)) => {
+                return writeln!(out, "{} doesn't fit in {}", value, register)
+            }
+            ("zero", _) => self.cpu.flags.zero = value != 0,
+            ("overflow", _) => self.cpu.flags.overflow = value != 0,
+            ("carry", _) => self.cpu.flags.carry = value != 0,
+            ("negative", _) => self.cpu.flags.negative = value != 0,
+            ("ie", _) => self.cpu.flags.interrupt_enable = value != 0,
+            _ => return writeln!(out, "unknown register {:?}", register),
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        u16::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    parse_number(s).and_then(|value| u8::try_from(value).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn debugger(program: &[u8]) -> Debugger {
+        let mut cpu = Cpu::new();
+        cpu.memory = Memory::new_with_instructions(program);
+        Debugger::new(cpu)
+    }
+
+    fn run_commands(debugger: &mut Debugger, commands: &str) -> String {
+        let mut output = Vec::new();
+        debugger.repl(commands.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        // SETV 10; STR 0x40; LDA 0x40; INC; EXIT 0
+        let mut debugger = debugger(&[0x05, 10, 0x07, 0x40, 0, 0x01, 0x40, 0, 0x03, 0x11, 0]);
+        let output = run_commands(&mut debugger, "step 0\n");
+        assert!(output.contains("invalid count \"0\""));
+        assert_eq!(debugger.cpu.ip, 0);
+
+        let output = run_commands(&mut debugger, "break 8\ncontinue\nregs\n");
+        assert!(output.contains("breakpoint: 0x0008: INC"));
+        assert!(output.contains("ip=0x0008 sp=0xFFFF acc=10"));
+        assert_eq!(debugger.cpu.accumulator, 10);
+
+        let output = run_commands(&mut debugger, "step\nc\nc\n");
+        assert!(output.contains("0x0009: EXIT 0"));
+        assert!(output.contains("program exited with code 0"));
+        assert!(output.contains("program has exited with code 0"));
+        assert_eq!(debugger.exit_code(), Some(0));
+        assert_eq!(debugger.cpu.accumulator, 11);
+    }
+
+    #[test]
+    fn test_next_steps_over_loops() {
+        // 0x00: NOP; 0x01: INC; 0x02: JO 7; 0x05: JMP 0; 0x08: NOP; 0x09: EXIT 7
+        let mut debugger = debugger(&[0x00, 0x03, 0x0F, 7, 0, 0x0B, 0, 0, 0x00, 0x11, 7]);
+        let output = run_commands(&mut debugger, "set acc 250
+n
+n
+n
+n
+regs
+");
+        assert!(output.contains("0x0001: INC"));
+        assert!(output.contains("0x0002: JO 7"));
+        assert!(output.contains("0x0005: JMP 0"));
+        // The whole loop runs until the accumulator overflows out of it
+        assert!(output.contains("0x0008: NOP"));
+        assert!(output.contains("ip=0x0008 sp=0xFFFF acc=0"));
+    }
+
+    #[test]
+    fn test_poke_and_mem() {
+        let mut debugger = debugger(&[0x11, 0]);
+        let output = run_commands(
+            &mut debugger,
+            "poke 0x1240 0xAB\nx 0x1240 4\nset acc 256\nset r3 7\nset r8 1\nbogus\nq\nregs\n",
+        );
+        assert!(output.contains("0x1240: AB 00 00 00"));
+        assert!(output.contains("256 doesn't fit in acc"));
+        assert_eq!(debugger.cpu.registers[3], 7);
+        assert!(output.contains("unknown register \"r8\""));
+        assert!(output.contains("unknown command \"bogus\""));
+        // Nothing after `quit` is executed
+        assert!(!output.contains("ip="));
+    }
+}
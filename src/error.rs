@@ -1,8 +1,33 @@
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CpuError {
-    MemoryError(u8),
+    MemoryError(usize),
     Exit(u8),
     VOverflow,
     AOverflow,
-    MalformedInput(u8, u8),
+    MalformedInput(u8),
+    OutputError(std::io::ErrorKind),
+    InputError(std::io::ErrorKind),
+    StackOverflow,
+    StackUnderflow,
+    DivideByZero,
+    /// A write to an address mapped to ROM
+    ReadOnly(u16),
 }
+impl std::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CpuError::MemoryError(addr) => write!(f, "invalid memory access at 0x{:04X}", addr),
+            CpuError::Exit(code) => write!(f, "program exited with code {}", code),
+            CpuError::VOverflow => write!(f, "value overflow"),
+            CpuError::AOverflow => write!(f, "instruction pointer ran past the end of memory"),
+            CpuError::MalformedInput(byte) => write!(f, "malformed instruction 0x{:02X}", byte),
+            CpuError::OutputError(kind) => write!(f, "failed to write output: {}", kind),
+            CpuError::InputError(kind) => write!(f, "failed to read input: {}", kind),
+            CpuError::StackOverflow => write!(f, "stack overflow"),
+            CpuError::StackUnderflow => write!(f, "pop from an empty stack"),
+            CpuError::DivideByZero => write!(f, "division by zero"),
+            CpuError::ReadOnly(addr) => write!(f, "write to read-only memory at 0x{:04X}", addr),
+        }
+    }
+}
+impl std::error::Error for CpuError {}
@@ -1,26 +1,61 @@
-#[derive(Default)]
+/// The status register. Flags keep their value until an instruction that affects them runs;
+/// which instructions set or clear which flag is documented in the ISA table in `.SPEC`.
+#[derive(Default, Clone, Copy, PartialEq)]
 pub struct Flags {
+    /// The last result was 0
     pub zero: bool,
+    /// The last result didn't fit in 8 bits, or input ran out
     pub overflow: bool,
-    pub clear: bool,
+    /// The carry out of an addition or shift, or the borrow of a subtraction
+    pub carry: bool,
+    /// Bit 7 of the last result
+    pub negative: bool,
+    /// Whether pending IRQs are serviced, off until the program executes `EI`
+    pub interrupt_enable: bool,
 }
 impl Flags {
+    /// Whether zero or overflow is set, the flags `JC` has always tested
     pub fn any(&self) -> bool {
         self.zero || self.overflow
     }
 
-    pub fn clear_flags(&mut self) {
-        self.zero = false;
-        self.overflow = false;
-        self.clear = false;
+    /// Set zero and negative from a result written to a register
+    pub fn set_result(&mut self, value: u8) {
+        self.zero = value == 0;
+        self.negative = value & 0x80 != 0;
+    }
+
+    /// Pack the flags into a byte, to save them on the stack
+    pub fn to_byte(&self) -> u8 {
+        [
+            self.zero,
+            self.overflow,
+            self.carry,
+            self.negative,
+            self.interrupt_enable,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |byte, (bit, set)| byte | (*set as u8) << bit)
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        let bit = |n: u8| byte & (1 << n) != 0;
+        Self {
+            zero: bit(0),
+            overflow: bit(1),
+            carry: bit(2),
+            negative: bit(3),
+            interrupt_enable: bit(4),
+        }
     }
 }
 impl std::fmt::Debug for Flags {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Flags {{\n\t\tzero: {};\n\t\toverflow: {};\n\t\tclear: {};\n\t}}",
-            self.zero, self.overflow, self.clear,
+            "Flags {{\n\t\tzero: {};\n\t\toverflow: {};\n\t\tcarry: {};\n\t\tnegative: {};\n\t\tinterrupt_enable: {};\n\t}}",
+            self.zero, self.overflow, self.carry, self.negative, self.interrupt_enable,
         )
     }
 }
@@ -1,21 +1,47 @@
-use crate::asm::error::AssemblerError;
-use crate::cpu::Cpu;
+use crate::cpu::{AddressMode, Cpu};
 use crate::error::CpuError;
+use crate::flags::Flags;
+use crate::REGISTERS;
 use std::convert::TryFrom;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
-    LDA(u8),
-    STA(u8),
+    LDA(u16),
+    STA(u16),
     SETV(u8),
-    SETA(u8),
-    STR(u8),
-    LOAD(u8),
-    JMP(u8),
-    JC(u8),
-    JZ(u8),
-    JO(u8),
+    SETA(u16),
+    STR(u16),
+    LOAD(u16),
+    JMP(u16),
+    /// Zero or overflow set, kept from the original instruction set
+    JC(u16),
+    JCS(u16),
+    JZ(u16),
+    JO(u16),
+    JNZ(u16),
+    JNO(u16),
+    JNC(u16),
+    JN(u16),
+    JNN(u16),
+    /// Unsigned `acc < usr` after `CMP`
+    JLT(u16),
+    JGE(u16),
+    JGT(u16),
+    JLE(u16),
     EXIT(u8),
+    CALL(u16),
+    /// `LDA [ptr]`, through the address stored at `ptr`
+    LDAI(u16),
+    STAI(u16),
+    SETAI(u16),
+    STRI(u16),
+    LOADI(u16),
+    /// `LDA base,U`, at `base` plus the user register
+    LDAX(u16),
+    STAX(u16),
+    SETAX(u16),
+    STRX(u16),
+    LOADX(u16),
     INC,
     DEC,
     ADD,
@@ -23,81 +49,351 @@ pub enum Instruction {
     NOP,
     OUT,
     CLN,
+    IN,
+    CMP,
+    MUL,
+    DIV,
+    MOD,
+    EI,
+    DI,
+    IRET,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    ROL,
+    ROR,
+    PUSHA,
+    PUSHU,
+    POPA,
+    POPU,
+    RET,
+    /// `MOV dst, src`
+    MOV(Register, Register),
+    /// `MOV dst, value`
+    MOVI(Register, u8),
+    /// `ADD dst, src`, storing `dst + src` in `dst`
+    ADDR(Register, Register),
+    SUBR(Register, Register),
+    CMPR(Register, Register),
+    ANDR(Register, Register),
+    ORR(Register, Register),
+    XORR(Register, Register),
+    INCR(Register),
+    DECR(Register),
 }
+
+/// An operand of the register forms: one of the general-purpose registers, or the accumulator
+/// or user register. Encoded in four bits, 0 to 7 for R0 to R7, 8 for A and 9 for U.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    R(GeneralRegister),
+    A,
+    U,
+}
+
+/// One of R0 to R7. Only `Register`'s constructors make one, so it is always in range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeneralRegister(u8);
+impl GeneralRegister {
+    /// 0 for R0 up to 7 for R7
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Register {
+    /// General-purpose register `idx`, if there is one
+    pub fn r(idx: u8) -> Option<Self> {
+        ((idx as usize) < REGISTERS).then_some(Register::R(GeneralRegister(idx)))
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x8 => Some(Register::A),
+            0x9 => Some(Register::U),
+            code => Register::r(code),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            Register::R(register) => register.0,
+            Register::A => 0x8,
+            Register::U => 0x9,
+        }
+    }
+
+    /// `R0` to `R7`, `A` or `U`, in any case
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_uppercase().as_str() {
+            "A" => Some(Register::A),
+            "U" => Some(Register::U),
+            name => name
+                .strip_prefix('R')
+                .and_then(|idx| idx.parse::<u8>().ok())
+                .and_then(Register::r),
+        }
+    }
+}
+impl std::fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Register::R(register) => write!(f, "R{}", register.0),
+            Register::A => write!(f, "A"),
+            Register::U => write!(f, "U"),
+        }
+    }
+}
+
 impl Instruction {
+    /// Execute the instruction. The flags each instruction sets or clears are listed in the ISA
+    /// table in `.SPEC`; anything not listed there is left unchanged.
     pub fn execute(&self, cpu: &mut Cpu) -> Result<(), CpuError> {
         match self {
             Instruction::LDA(v) => {
-                cpu.accumulator = cpu.memory.get(*v)?;
-            }
-            Instruction::STA(v) => {
-                cpu.memory.set(*v, cpu.accumulator)?;
+                let value = cpu.read(*v)?;
+                load_accumulator(cpu, value)
             }
+            Instruction::STA(v) => cpu.write(*v, cpu.accumulator)?,
             Instruction::INC => {
-                if cpu.accumulator == 255 {
-                    cpu.flags.zero = true;
-                    cpu.flags.overflow = true
-                }
-                cpu.accumulator = cpu.accumulator.wrapping_add(1)
+                cpu.flags.overflow = cpu.accumulator == 255;
+                load_accumulator(cpu, cpu.accumulator.wrapping_add(1))
             }
             Instruction::DEC => {
-                if cpu.accumulator == 1 {
-                    cpu.flags.zero = true
-                } else if cpu.accumulator == 0 {
-                    cpu.flags.overflow = true
-                }
-                cpu.accumulator = cpu.accumulator.wrapping_sub(1)
+                cpu.flags.overflow = cpu.accumulator == 0;
+                load_accumulator(cpu, cpu.accumulator.wrapping_sub(1))
             }
-            Instruction::SETV(v) => cpu.user = *v,
-            Instruction::SETA(v) => cpu.user = cpu.memory.get(*v)?,
-            Instruction::STR(v) => cpu.memory.set(*v, cpu.user)?,
-            Instruction::LOAD(v) => cpu.user = cpu.memory.get(*v)?,
+            Instruction::SETV(v) => load_user(cpu, *v),
+            Instruction::SETA(v) | Instruction::LOAD(v) => {
+                let value = cpu.read(*v)?;
+                load_user(cpu, value)
+            }
+            Instruction::STR(v) => cpu.write(*v, cpu.user)?,
             Instruction::ADD => {
-                if let Some(acc) = cpu.accumulator.checked_add(cpu.user) {
-                    cpu.accumulator = acc;
-                } else {
-                    cpu.flags.overflow = true;
-                    cpu.accumulator = cpu.accumulator.wrapping_add(cpu.user)
-                }
+                let (acc, carry) = cpu.accumulator.overflowing_add(cpu.user);
+                cpu.flags.carry = carry;
+                cpu.flags.overflow = carry;
+                load_accumulator(cpu, acc)
+            }
+            Instruction::SUB => {
+                compare(cpu, cpu.accumulator, cpu.user);
+                load_accumulator(cpu, cpu.accumulator.wrapping_sub(cpu.user))
             }
-            Instruction::SUB => cpu.accumulator = cpu.accumulator.wrapping_sub(cpu.user),
+            Instruction::CMP => compare(cpu, cpu.accumulator, cpu.user),
             Instruction::JMP(v) => cpu.ip = *v,
-            Instruction::JC(v) => {
-                if cpu.flags.any() {
+            Instruction::JC(v)
+            | Instruction::JCS(v)
+            | Instruction::JZ(v)
+            | Instruction::JO(v)
+            | Instruction::JNZ(v)
+            | Instruction::JNO(v)
+            | Instruction::JNC(v)
+            | Instruction::JN(v)
+            | Instruction::JNN(v)
+            | Instruction::JLT(v)
+            | Instruction::JGE(v)
+            | Instruction::JGT(v)
+            | Instruction::JLE(v) => {
+                if self.condition(&cpu.flags) {
                     cpu.ip = *v
                 }
             }
-            Instruction::JZ(v) => {
-                if cpu.flags.zero {
-                    cpu.ip = *v
+            Instruction::OUT => cpu
+                .output
+                .output(cpu.accumulator)
+                .map_err(|e| CpuError::OutputError(e.kind()))?,
+            Instruction::NOP => (),
+            Instruction::EXIT(ex_code) => return Err(CpuError::Exit(*ex_code)),
+            Instruction::CLN => load_accumulator(cpu, cpu.user),
+            Instruction::IN => match cpu.input.input() {
+                Ok(Some(value)) => {
+                    cpu.flags.overflow = false;
+                    load_accumulator(cpu, value)
+                }
+                Ok(None) => {
+                    // End of input: 0, with overflow raised so `JO` can detect it
+                    cpu.flags.overflow = true;
+                    load_accumulator(cpu, 0)
                 }
+                Err(e) => return Err(CpuError::InputError(e.kind())),
+            },
+            Instruction::MUL => {
+                let (acc, overflow) = cpu.accumulator.overflowing_mul(cpu.user);
+                cpu.flags.carry = overflow;
+                cpu.flags.overflow = overflow;
+                load_accumulator(cpu, acc)
             }
-            Instruction::JO(v) => {
-                if cpu.flags.overflow {
-                    cpu.ip = *v
+            Instruction::DIV | Instruction::MOD => {
+                let result = match self {
+                    Instruction::DIV => cpu.accumulator.checked_div(cpu.user),
+                    _ => cpu.accumulator.checked_rem(cpu.user),
+                };
+                match result {
+                    Some(acc) => {
+                        cpu.flags.carry = false;
+                        cpu.flags.overflow = false;
+                        load_accumulator(cpu, acc)
+                    }
+                    None => return Err(CpuError::DivideByZero),
                 }
             }
-            Instruction::OUT => println!("{}", cpu.accumulator),
-            Instruction::NOP => (),
-            Instruction::EXIT(ex_code) => return Err(CpuError::Exit(*ex_code)),
-            Instruction::CLN => cpu.accumulator = cpu.user,
+            Instruction::EI => cpu.flags.interrupt_enable = true,
+            Instruction::DI => cpu.flags.interrupt_enable = false,
+            Instruction::IRET => {
+                let flags = cpu.pop()?;
+                let ip = cpu.pop_address()?;
+                cpu.flags = Flags::from_byte(flags);
+                // `step` moves past the IRET, landing back on the interrupted instruction
+                cpu.ip = ip.wrapping_sub(1);
+            }
+            Instruction::AND => load_accumulator(cpu, cpu.accumulator & cpu.user),
+            Instruction::OR => load_accumulator(cpu, cpu.accumulator | cpu.user),
+            Instruction::XOR => load_accumulator(cpu, cpu.accumulator ^ cpu.user),
+            Instruction::NOT => load_accumulator(cpu, !cpu.accumulator),
+            Instruction::SHL => shift(cpu, cpu.accumulator & 0x80 != 0, cpu.accumulator << 1),
+            Instruction::SHR => shift(cpu, cpu.accumulator & 0x01 != 0, cpu.accumulator >> 1),
+            Instruction::ROL => shift(
+                cpu,
+                cpu.accumulator & 0x80 != 0,
+                cpu.accumulator.rotate_left(1),
+            ),
+            Instruction::ROR => shift(
+                cpu,
+                cpu.accumulator & 0x01 != 0,
+                cpu.accumulator.rotate_right(1),
+            ),
+            Instruction::PUSHA => cpu.push(cpu.accumulator)?,
+            Instruction::PUSHU => cpu.push(cpu.user)?,
+            Instruction::POPA => {
+                let value = cpu.pop()?;
+                load_accumulator(cpu, value)
+            }
+            Instruction::POPU => {
+                let value = cpu.pop()?;
+                load_user(cpu, value)
+            }
+            Instruction::CALL(v) => {
+                // `ip` is on the last byte of the CALL, so returning here resumes just after it
+                cpu.push_address(cpu.ip)?;
+                cpu.ip = *v;
+            }
+            Instruction::RET => cpu.ip = cpu.pop_address()?,
+            Instruction::LDAI(v) => {
+                let addr = cpu.read_address(*v)?;
+                let value = cpu.read(addr)?;
+                load_accumulator(cpu, value)
+            }
+            Instruction::STAI(v) => {
+                let addr = cpu.read_address(*v)?;
+                cpu.write(addr, cpu.accumulator)?
+            }
+            Instruction::SETAI(v) | Instruction::LOADI(v) => {
+                let addr = cpu.read_address(*v)?;
+                let value = cpu.read(addr)?;
+                load_user(cpu, value)
+            }
+            Instruction::STRI(v) => {
+                let addr = cpu.read_address(*v)?;
+                cpu.write(addr, cpu.user)?
+            }
+            Instruction::LDAX(v) => {
+                let value = cpu.read(indexed(cpu, *v))?;
+                load_accumulator(cpu, value)
+            }
+            Instruction::STAX(v) => cpu.write(indexed(cpu, *v), cpu.accumulator)?,
+            Instruction::SETAX(v) | Instruction::LOADX(v) => {
+                let value = cpu.read(indexed(cpu, *v))?;
+                load_user(cpu, value)
+            }
+            Instruction::STRX(v) => cpu.write(indexed(cpu, *v), cpu.user)?,
+            Instruction::MOV(dst, src) => load_register(cpu, *dst, cpu.register(*src)),
+            Instruction::MOVI(dst, v) => load_register(cpu, *dst, *v),
+            Instruction::ADDR(dst, src) => {
+                let (value, carry) = cpu.register(*dst).overflowing_add(cpu.register(*src));
+                cpu.flags.carry = carry;
+                cpu.flags.overflow = carry;
+                load_register(cpu, *dst, value)
+            }
+            Instruction::SUBR(dst, src) => {
+                let (lhs, rhs) = (cpu.register(*dst), cpu.register(*src));
+                compare(cpu, lhs, rhs);
+                load_register(cpu, *dst, lhs.wrapping_sub(rhs))
+            }
+            Instruction::CMPR(lhs, rhs) => compare(cpu, cpu.register(*lhs), cpu.register(*rhs)),
+            Instruction::ANDR(dst, src) => {
+                load_register(cpu, *dst, cpu.register(*dst) & cpu.register(*src))
+            }
+            Instruction::ORR(dst, src) => {
+                load_register(cpu, *dst, cpu.register(*dst) | cpu.register(*src))
+            }
+            Instruction::XORR(dst, src) => {
+                load_register(cpu, *dst, cpu.register(*dst) ^ cpu.register(*src))
+            }
+            Instruction::INCR(register) => {
+                let value = cpu.register(*register);
+                cpu.flags.overflow = value == 255;
+                load_register(cpu, *register, value.wrapping_add(1))
+            }
+            Instruction::DECR(register) => {
+                let value = cpu.register(*register);
+                cpu.flags.overflow = value == 0;
+                load_register(cpu, *register, value.wrapping_sub(1))
+            }
         }
         Ok(())
     }
 
+    /// Whether a conditional jump is taken
+    fn condition(&self, flags: &Flags) -> bool {
+        match self {
+            Instruction::JZ(_) => flags.zero,
+            Instruction::JNZ(_) => !flags.zero,
+            Instruction::JO(_) => flags.overflow,
+            Instruction::JNO(_) => !flags.overflow,
+            Instruction::JC(_) => flags.any(),
+            Instruction::JCS(_) | Instruction::JLT(_) => flags.carry,
+            Instruction::JNC(_) | Instruction::JGE(_) => !flags.carry,
+            Instruction::JN(_) => flags.negative,
+            Instruction::JNN(_) => !flags.negative,
+            Instruction::JGT(_) => !flags.carry && !flags.zero,
+            Instruction::JLE(_) => flags.carry || flags.zero,
+            _ => false,
+        }
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         match self {
             Instruction::EXIT(ex_code) => vec![0x11, *ex_code],
-            Instruction::STA(v) => vec![0x02, *v],
-            Instruction::LDA(v) => vec![0x01, *v],
+            Instruction::STA(v) => address(0x02, *v),
+            Instruction::LDA(v) => address(0x01, *v),
             Instruction::SETV(v) => vec![0x05, *v],
-            Instruction::SETA(v) => vec![0x06, *v],
-            Instruction::STR(v) => vec![0x07, *v],
-            Instruction::LOAD(v) => vec![0x08, *v],
-            Instruction::JMP(v) => vec![0x0B, *v],
-            Instruction::JC(v) => vec![0x0C, *v],
-            Instruction::JZ(v) => vec![0x0E, *v],
-            Instruction::JO(v) => vec![0x0F, *v],
+            Instruction::SETA(v) => address(0x06, *v),
+            Instruction::STR(v) => address(0x07, *v),
+            Instruction::LOAD(v) => address(0x08, *v),
+            Instruction::JMP(v) => address(0x0B, *v),
+            Instruction::JC(v) => address(0x0C, *v),
+            Instruction::JZ(v) => address(0x0E, *v),
+            Instruction::JO(v) => address(0x0F, *v),
+            Instruction::JNZ(v) => address(0x0D, *v),
+            Instruction::JNO(v) => address(0x2C, *v),
+            Instruction::JNC(v) => address(0x2D, *v),
+            Instruction::JN(v) => address(0x2E, *v),
+            Instruction::JNN(v) => address(0x2F, *v),
+            Instruction::JLT(v) => address(0x30, *v),
+            Instruction::JGE(v) => address(0x31, *v),
+            Instruction::JGT(v) => address(0x32, *v),
+            Instruction::JLE(v) => address(0x33, *v),
+            Instruction::JCS(v) => address(0x45, *v),
+            Instruction::CMP => vec![0x34],
+            Instruction::MUL => vec![0x35],
+            Instruction::DIV => vec![0x36],
+            Instruction::MOD => vec![0x37],
+            Instruction::EI => vec![0x38],
+            Instruction::DI => vec![0x39],
+            Instruction::IRET => vec![0x3A],
             Instruction::INC => vec![0x03],
             Instruction::DEC => vec![0x04],
             Instruction::ADD => vec![0x09],
@@ -105,6 +401,87 @@ impl Instruction {
             Instruction::OUT => vec![0x10],
             Instruction::NOP => vec![0x00],
             Instruction::CLN => vec![0x12],
+            Instruction::IN => vec![0x13],
+            Instruction::PUSHA => vec![0x14],
+            Instruction::AND => vec![0x24],
+            Instruction::OR => vec![0x25],
+            Instruction::XOR => vec![0x26],
+            Instruction::NOT => vec![0x27],
+            Instruction::SHL => vec![0x28],
+            Instruction::SHR => vec![0x29],
+            Instruction::ROL => vec![0x2A],
+            Instruction::ROR => vec![0x2B],
+            Instruction::PUSHU => vec![0x15],
+            Instruction::POPA => vec![0x16],
+            Instruction::POPU => vec![0x17],
+            Instruction::CALL(v) => address(0x18, *v),
+            Instruction::RET => vec![0x19],
+            Instruction::LDAI(v) => address(0x1A, *v),
+            Instruction::STAI(v) => address(0x1B, *v),
+            Instruction::SETAI(v) => address(0x1C, *v),
+            Instruction::STRI(v) => address(0x1D, *v),
+            Instruction::LOADI(v) => address(0x1E, *v),
+            Instruction::LDAX(v) => address(0x1F, *v),
+            Instruction::STAX(v) => address(0x20, *v),
+            Instruction::SETAX(v) => address(0x21, *v),
+            Instruction::STRX(v) => address(0x22, *v),
+            Instruction::LOADX(v) => address(0x23, *v),
+            Instruction::MOV(dst, src) => vec![0x3B, pair(*dst, *src)],
+            Instruction::MOVI(dst, v) => vec![0x3C, dst.code(), *v],
+            Instruction::ADDR(dst, src) => vec![0x3D, pair(*dst, *src)],
+            Instruction::SUBR(dst, src) => vec![0x3E, pair(*dst, *src)],
+            Instruction::CMPR(lhs, rhs) => vec![0x3F, pair(*lhs, *rhs)],
+            Instruction::ANDR(dst, src) => vec![0x40, pair(*dst, *src)],
+            Instruction::ORR(dst, src) => vec![0x41, pair(*dst, *src)],
+            Instruction::XORR(dst, src) => vec![0x42, pair(*dst, *src)],
+            Instruction::INCR(register) => vec![0x43, register.code()],
+            Instruction::DECR(register) => vec![0x44, register.code()],
+        }
+    }
+
+    /// Bytes of operand that follow `opcode` in `mode`, or `None` if it isn't an opcode.
+    /// Values and registers are always one byte, addresses are as wide as the mode's.
+    pub fn operand_size(opcode: u8, mode: AddressMode) -> Option<usize> {
+        if Self::from_byte(opcode).is_some() {
+            return Some(0);
+        }
+        match Self::from_byte_and_arg(opcode, 0) {
+            Ok(Self::MOVI(..)) => Some(2),
+            Ok(
+                Self::SETV(_)
+                | Self::EXIT(_)
+                | Self::MOV(..)
+                | Self::ADDR(..)
+                | Self::SUBR(..)
+                | Self::CMPR(..)
+                | Self::ANDR(..)
+                | Self::ORR(..)
+                | Self::XORR(..)
+                | Self::INCR(_)
+                | Self::DECR(_),
+            ) => Some(1),
+            Ok(_) => Some(mode.address_size()),
+            Err(_) => None,
+        }
+    }
+
+    /// Bytes the instruction takes up in `mode`
+    pub fn size(&self, mode: AddressMode) -> usize {
+        let opcode = self.as_bytes()[0];
+        1 + Self::operand_size(opcode, mode).unwrap_or(0)
+    }
+
+    /// Decode `opcode` followed by its little-endian `operand`, as many bytes as `operand_size`
+    pub fn decode(opcode: u8, operand: &[u8]) -> Result<Self, CpuError> {
+        match Self::from_byte(opcode) {
+            Some(single_byte) => Ok(single_byte),
+            None => {
+                let arg = operand
+                    .iter()
+                    .rev()
+                    .fold(0, |arg, byte| arg << 8 | *byte as u16);
+                Self::from_byte_and_arg(opcode, arg)
+            }
         }
     }
 
@@ -117,16 +494,38 @@ impl Instruction {
             0x0A => Some(Self::SUB),
             0x10 => Some(Self::OUT),
             0x12 => Some(Self::CLN),
+            0x13 => Some(Self::IN),
+            0x14 => Some(Self::PUSHA),
+            0x15 => Some(Self::PUSHU),
+            0x16 => Some(Self::POPA),
+            0x17 => Some(Self::POPU),
+            0x19 => Some(Self::RET),
+            0x24 => Some(Self::AND),
+            0x25 => Some(Self::OR),
+            0x26 => Some(Self::XOR),
+            0x27 => Some(Self::NOT),
+            0x28 => Some(Self::SHL),
+            0x29 => Some(Self::SHR),
+            0x2A => Some(Self::ROL),
+            0x2B => Some(Self::ROR),
+            0x34 => Some(Self::CMP),
+            0x35 => Some(Self::MUL),
+            0x36 => Some(Self::DIV),
+            0x37 => Some(Self::MOD),
+            0x38 => Some(Self::EI),
+            0x39 => Some(Self::DI),
+            0x3A => Some(Self::IRET),
             _ => None,
         }
     }
 
-    pub fn from_byte_and_arg(byte: u8, arg: u8) -> Result<Self, CpuError> {
+    pub fn from_byte_and_arg(byte: u8, arg: u16) -> Result<Self, CpuError> {
+        let value = u8::try_from(arg).map_err(|_| CpuError::MalformedInput(byte));
         match byte {
             0x00 => Ok(Self::NOP),
             0x01 => Ok(Self::LDA(arg)),
             0x02 => Ok(Self::STA(arg)),
-            0x05 => Ok(Self::SETV(arg)),
+            0x05 => Ok(Self::SETV(value?)),
             0x06 => Ok(Self::SETA(arg)),
             0x07 => Ok(Self::STR(arg)),
             0x08 => Ok(Self::LOAD(arg)),
@@ -134,8 +533,40 @@ impl Instruction {
             0x0C => Ok(Self::JC(arg)),
             0x0E => Ok(Self::JZ(arg)),
             0x0F => Ok(Self::JO(arg)),
-            0x11 => Ok(Self::EXIT(arg)),
-            _ => Err(CpuError::MalformedInput(byte, arg)),
+            0x0D => Ok(Self::JNZ(arg)),
+            0x2C => Ok(Self::JNO(arg)),
+            0x2D => Ok(Self::JNC(arg)),
+            0x2E => Ok(Self::JN(arg)),
+            0x2F => Ok(Self::JNN(arg)),
+            0x30 => Ok(Self::JLT(arg)),
+            0x31 => Ok(Self::JGE(arg)),
+            0x32 => Ok(Self::JGT(arg)),
+            0x33 => Ok(Self::JLE(arg)),
+            0x45 => Ok(Self::JCS(arg)),
+            0x11 => Ok(Self::EXIT(value?)),
+            0x18 => Ok(Self::CALL(arg)),
+            0x1A => Ok(Self::LDAI(arg)),
+            0x1B => Ok(Self::STAI(arg)),
+            0x1C => Ok(Self::SETAI(arg)),
+            0x1D => Ok(Self::STRI(arg)),
+            0x1E => Ok(Self::LOADI(arg)),
+            0x1F => Ok(Self::LDAX(arg)),
+            0x20 => Ok(Self::STAX(arg)),
+            0x21 => Ok(Self::SETAX(arg)),
+            0x22 => Ok(Self::STRX(arg)),
+            0x23 => Ok(Self::LOADX(arg)),
+            0x3B => register_pair(byte, value?).map(|(dst, src)| Self::MOV(dst, src)),
+            // The register, then the value
+            0x3C => Ok(Self::MOVI(register(byte, arg as u8)?, (arg >> 8) as u8)),
+            0x3D => register_pair(byte, value?).map(|(dst, src)| Self::ADDR(dst, src)),
+            0x3E => register_pair(byte, value?).map(|(dst, src)| Self::SUBR(dst, src)),
+            0x3F => register_pair(byte, value?).map(|(lhs, rhs)| Self::CMPR(lhs, rhs)),
+            0x40 => register_pair(byte, value?).map(|(dst, src)| Self::ANDR(dst, src)),
+            0x41 => register_pair(byte, value?).map(|(dst, src)| Self::ORR(dst, src)),
+            0x42 => register_pair(byte, value?).map(|(dst, src)| Self::XORR(dst, src)),
+            0x43 => Ok(Self::INCR(register(byte, value?)?)),
+            0x44 => Ok(Self::DECR(register(byte, value?)?)),
+            _ => Err(CpuError::MalformedInput(byte)),
         }
     }
 }
@@ -144,29 +575,296 @@ impl From<Instruction> for String {
         match ins {
             Instruction::LDA(v) => format!("LDA {}", v),
             Instruction::STA(v) => format!("STA {}", v),
-            Instruction::INC => format!("INC"),
-            Instruction::DEC => format!("DEC"),
+            Instruction::INC => "INC".to_owned(),
+            Instruction::DEC => "DEC".to_owned(),
             Instruction::SETV(v) => format!("SETV {}", v),
             Instruction::SETA(v) => format!("SETA {}", v),
             Instruction::STR(v) => format!("STR {}", v),
             Instruction::LOAD(v) => format!("LOAD {}", v),
-            Instruction::ADD => format!("ADD"),
-            Instruction::SUB => format!("SUB"),
+            Instruction::ADD => "ADD".to_owned(),
+            Instruction::SUB => "SUB".to_owned(),
             Instruction::JMP(v) => format!("JMP {}", v),
             Instruction::JC(v) => format!("JC {}", v),
             Instruction::JZ(v) => format!("JZ {}", v),
             Instruction::JO(v) => format!("JO {}", v),
-            Instruction::OUT => format!("OUT"),
-            Instruction::NOP => format!("NOP"),
+            Instruction::JNZ(v) => format!("JNZ {}", v),
+            Instruction::JNO(v) => format!("JNO {}", v),
+            Instruction::JNC(v) => format!("JNC {}", v),
+            Instruction::JN(v) => format!("JN {}", v),
+            Instruction::JNN(v) => format!("JNN {}", v),
+            Instruction::JLT(v) => format!("JLT {}", v),
+            Instruction::JGE(v) => format!("JGE {}", v),
+            Instruction::JGT(v) => format!("JGT {}", v),
+            Instruction::JLE(v) => format!("JLE {}", v),
+            Instruction::JCS(v) => format!("JCS {}", v),
+            Instruction::CMP => "CMP".to_owned(),
+            Instruction::MUL => "MUL".to_owned(),
+            Instruction::DIV => "DIV".to_owned(),
+            Instruction::MOD => "MOD".to_owned(),
+            Instruction::EI => "EI".to_owned(),
+            Instruction::DI => "DI".to_owned(),
+            Instruction::IRET => "IRET".to_owned(),
+            Instruction::OUT => "OUT".to_owned(),
+            Instruction::NOP => "NOP".to_owned(),
             Instruction::EXIT(v) => format!("EXIT {}", v),
-            Instruction::CLN => format!("CLN"),
+            Instruction::CLN => "CLN".to_owned(),
+            Instruction::IN => "IN".to_owned(),
+            Instruction::AND => "AND".to_owned(),
+            Instruction::OR => "OR".to_owned(),
+            Instruction::XOR => "XOR".to_owned(),
+            Instruction::NOT => "NOT".to_owned(),
+            Instruction::SHL => "SHL".to_owned(),
+            Instruction::SHR => "SHR".to_owned(),
+            Instruction::ROL => "ROL".to_owned(),
+            Instruction::ROR => "ROR".to_owned(),
+            Instruction::PUSHA => "PUSH A".to_owned(),
+            Instruction::PUSHU => "PUSH U".to_owned(),
+            Instruction::POPA => "POP A".to_owned(),
+            Instruction::POPU => "POP U".to_owned(),
+            Instruction::CALL(v) => format!("CALL {}", v),
+            Instruction::RET => "RET".to_owned(),
+            Instruction::LDAI(v) => format!("LDA [{}]", v),
+            Instruction::STAI(v) => format!("STA [{}]", v),
+            Instruction::SETAI(v) => format!("SETA [{}]", v),
+            Instruction::STRI(v) => format!("STR [{}]", v),
+            Instruction::LOADI(v) => format!("LOAD [{}]", v),
+            Instruction::LDAX(v) => format!("LDA {},U", v),
+            Instruction::STAX(v) => format!("STA {},U", v),
+            Instruction::SETAX(v) => format!("SETA {},U", v),
+            Instruction::STRX(v) => format!("STR {},U", v),
+            Instruction::LOADX(v) => format!("LOAD {},U", v),
+            Instruction::MOV(dst, src) => format!("MOV {}, {}", dst, src),
+            Instruction::MOVI(dst, v) => format!("MOV {}, {}", dst, v),
+            Instruction::ADDR(dst, src) => format!("ADD {}, {}", dst, src),
+            Instruction::SUBR(dst, src) => format!("SUB {}, {}", dst, src),
+            Instruction::CMPR(lhs, rhs) => format!("CMP {}, {}", lhs, rhs),
+            Instruction::ANDR(dst, src) => format!("AND {}, {}", dst, src),
+            Instruction::ORR(dst, src) => format!("OR {}, {}", dst, src),
+            Instruction::XORR(dst, src) => format!("XOR {}, {}", dst, src),
+            Instruction::INCR(register) => format!("INC {}", register),
+            Instruction::DECR(register) => format!("DEC {}", register),
+        }
+    }
+}
+/// An instruction with a two byte, little-endian address operand
+fn address(opcode: u8, addr: u16) -> Vec<u8> {
+    let [low, high] = addr.to_le_bytes();
+    vec![opcode, low, high]
+}
+
+/// The operand byte of a register form, the destination in the high nibble
+fn pair(dst: Register, src: Register) -> u8 {
+    dst.code() << 4 | src.code()
+}
+
+/// Decode the register operand of `opcode`
+fn register(opcode: u8, code: u8) -> Result<Register, CpuError> {
+    Register::from_code(code).ok_or(CpuError::MalformedInput(opcode))
+}
+
+/// Decode the `(dst, src)` operand byte of `opcode`
+fn register_pair(opcode: u8, byte: u8) -> Result<(Register, Register), CpuError> {
+    Ok((register(opcode, byte >> 4)?, register(opcode, byte & 0xF)?))
+}
+
+/// Write `value` to the accumulator, setting zero and negative from it
+fn load_accumulator(cpu: &mut Cpu, value: u8) {
+    cpu.accumulator = value;
+    cpu.flags.set_result(value);
+}
+
+/// Write `value` to the user register, setting zero and negative from it
+fn load_user(cpu: &mut Cpu, value: u8) {
+    cpu.user = value;
+    cpu.flags.set_result(value);
+}
+
+/// Write `value` to `register`, setting zero and negative from it
+fn load_register(cpu: &mut Cpu, register: Register, value: u8) {
+    cpu.set_register(register, value);
+    cpu.flags.set_result(value);
+}
+
+/// Set the flags from `lhs - rhs`: carry is the borrow, and overflow is cleared
+fn compare(cpu: &mut Cpu, lhs: u8, rhs: u8) {
+    cpu.flags.carry = lhs < rhs;
+    cpu.flags.overflow = false;
+    cpu.flags.set_result(lhs.wrapping_sub(rhs));
+}
+
+/// Store the result of a shift or rotate, with the bit shifted out in carry
+fn shift(cpu: &mut Cpu, carry: bool, result: u8) {
+    cpu.flags.carry = carry;
+    load_accumulator(cpu, result);
+}
+
+/// `base` offset by the user register, wrapping around at the top of the address space
+fn indexed(cpu: &Cpu, base: u16) -> u16 {
+    match cpu.mode {
+        AddressMode::Wide => base.wrapping_add(cpu.user as u16),
+        AddressMode::Narrow => (base as u8).wrapping_add(cpu.user) as u16,
+    }
+}
+
+/// How a memory instruction's argument is turned into an address
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// `LDA addr`
+    Absolute,
+    /// `LDA [ptr]`
+    Indirect,
+    /// `LDA base,U`
+    Indexed,
+}
+impl Mode {
+    /// Split the addressing syntax off an argument, leaving the bare address or label
+    fn parse(arg: &str) -> (Self, &str) {
+        if let Some(inner) = arg.strip_prefix('[').and_then(|arg| arg.strip_suffix(']')) {
+            return (Mode::Indirect, inner.trim());
+        }
+        match arg.split_once(',') {
+            Some((base, register)) if register.trim().eq_ignore_ascii_case("U") => {
+                (Mode::Indexed, base.trim())
+            }
+            _ => (Mode::Absolute, arg),
         }
     }
 }
+
+/// The opcode of an instruction with an argument, if `mnemonic` supports `mode`
+fn opcode(mnemonic: &str, mode: Mode) -> Option<u8> {
+    let opcode = match (mnemonic, mode) {
+        ("EXIT", Mode::Absolute) => 0x11,
+        ("STA", Mode::Absolute) => 0x02,
+        ("LDA", Mode::Absolute) => 0x01,
+        ("SETV", Mode::Absolute) => 0x05,
+        ("SETA", Mode::Absolute) => 0x06,
+        ("STR", Mode::Absolute) => 0x07,
+        ("LOAD", Mode::Absolute) => 0x08,
+        ("JMP", Mode::Absolute) => 0x0B,
+        ("JC", Mode::Absolute) => 0x0C,
+        ("JZ", Mode::Absolute) => 0x0E,
+        ("JO", Mode::Absolute) => 0x0F,
+        ("JNZ", Mode::Absolute) => 0x0D,
+        ("JNO", Mode::Absolute) => 0x2C,
+        ("JNC", Mode::Absolute) => 0x2D,
+        ("JN", Mode::Absolute) => 0x2E,
+        ("JNN", Mode::Absolute) => 0x2F,
+        ("JLT", Mode::Absolute) => 0x30,
+        ("JGE", Mode::Absolute) => 0x31,
+        ("JGT", Mode::Absolute) => 0x32,
+        ("JLE", Mode::Absolute) => 0x33,
+        ("JCS", Mode::Absolute) => 0x45,
+        ("CALL", Mode::Absolute) => 0x18,
+        ("LDA", Mode::Indirect) => 0x1A,
+        ("STA", Mode::Indirect) => 0x1B,
+        ("SETA", Mode::Indirect) => 0x1C,
+        ("STR", Mode::Indirect) => 0x1D,
+        ("LOAD", Mode::Indirect) => 0x1E,
+        ("LDA", Mode::Indexed) => 0x1F,
+        ("STA", Mode::Indexed) => 0x20,
+        ("SETA", Mode::Indexed) => 0x21,
+        ("STR", Mode::Indexed) => 0x22,
+        ("LOAD", Mode::Indexed) => 0x23,
+        _ => return None,
+    };
+    Some(opcode)
+}
+
+/// Whether `name` is an instruction mnemonic, in any addressing mode or register form
+pub(crate) fn is_mnemonic(name: &str) -> bool {
+    let mnemonic = name.to_uppercase();
+    Instruction::try_from(mnemonic.clone()).is_ok()
+        || [Mode::Absolute, Mode::Indirect, Mode::Indexed]
+            .iter()
+            .any(|mode| opcode(&mnemonic, *mode).is_some())
+        || matches!(mnemonic.as_str(), "PUSH" | "POP" | "MOV")
+}
+
+/// `Ok(None)` if `arg` is not a number, and so should be a label or expression
+pub(crate) fn parse_number(arg: &str) -> Result<Option<u16>, String> {
+    let parsed = if arg.chars().all(|c| c.is_numeric()) {
+        arg.parse::<u16>()
+    } else if let Some(hex) = arg.strip_prefix("0x").filter(|hex| is_digits(hex, 16)) {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = arg.strip_prefix("0b").filter(|bin| is_digits(bin, 2)) {
+        u16::from_str_radix(bin, 2)
+    } else {
+        return Ok(None);
+    };
+    match parsed {
+        Ok(num) => Ok(Some(num)),
+        Err(e) => Err(format!(
+            "Encountered an error parsing {} as u16: {}",
+            arg, e
+        )),
+    }
+}
+
+fn is_digits(s: &str, radix: u32) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_digit(radix))
+}
+
+/// Parse `operands` as a register form of `mnemonic`, `MOV R1, R2`, `ADD R0, A` or `INC R3`.
+/// `Ok(None)` if `mnemonic` has no register form.
+fn register_form(
+    mnemonic: &str,
+    operands: &str,
+) -> Result<Option<Instruction>, <Instruction as TryFrom<String>>::Error> {
+    let unary = matches!(mnemonic, "INC" | "DEC");
+    let binary = matches!(
+        mnemonic,
+        "MOV" | "ADD" | "SUB" | "CMP" | "AND" | "OR" | "XOR"
+    );
+    if !unary && !binary {
+        return Ok(None);
+    }
+    let register = |operand: &str| {
+        Register::parse(operand).ok_or_else(|| {
+            Err(format!(
+                "Expected a register for {}, found {}",
+                mnemonic, operand
+            ))
+        })
+    };
+    let operands = operands.split(',').map(str::trim).collect::<Vec<&str>>();
+    let instruction = match (mnemonic, operands.as_slice()) {
+        ("INC", [dst]) => Instruction::INCR(register(dst)?),
+        ("DEC", [dst]) => Instruction::DECR(register(dst)?),
+        ("MOV", [dst, src]) => match (Register::parse(src), parse_number(src)) {
+            (Some(src), _) => Instruction::MOV(register(dst)?, src),
+            (None, Ok(Some(value))) => match u8::try_from(value) {
+                Ok(value) => Instruction::MOVI(register(dst)?, value),
+                Err(_) => {
+                    return Err(Err(format!(
+                        "MOV takes a register or a value from 0 to 255, found {}",
+                        value
+                    )))
+                }
+            },
+            // The register is encoded before the value
+            (None, Ok(None)) => {
+                return Err(Ok((vec![0x3C, register(dst)?.code()], src.to_string())))
+            }
+            (None, Err(e)) => return Err(Err(e)),
+        },
+        ("ADD", [dst, src]) => Instruction::ADDR(register(dst)?, register(src)?),
+        ("SUB", [dst, src]) => Instruction::SUBR(register(dst)?, register(src)?),
+        ("CMP", [lhs, rhs]) => Instruction::CMPR(register(lhs)?, register(rhs)?),
+        ("AND", [dst, src]) => Instruction::ANDR(register(dst)?, register(src)?),
+        ("OR", [dst, src]) => Instruction::ORR(register(dst)?, register(src)?),
+        ("XOR", [dst, src]) => Instruction::XORR(register(dst)?, register(src)?),
+        _ if unary => return Err(Err(format!("Expected one register for {}", mnemonic))),
+        _ => return Err(Err(format!("Expected two registers for {}", mnemonic))),
+    };
+    Ok(Some(instruction))
+}
+
 impl TryFrom<String> for Instruction {
-    // Ok(instruction_byte, arg_string)
+    // Ok((encoded_bytes, operand_string)) for an operand only the assembler can evaluate,
+    // a label or expression, with the bytes encoded before it
     // Err(error_string)
-    type Error = Result<(u8, String), String>;
+    type Error = Result<(Vec<u8>, String), String>;
     fn try_from(s: String) -> Result<Instruction, Self::Error> {
         let s = s.trim();
         if s.contains(" ") {
@@ -176,74 +874,64 @@ impl TryFrom<String> for Instruction {
                 Some(is) => is,
                 None => unreachable!(),
             };
-            let arg_str = match parts.next() {
-                Some(arg) => arg,
-                None => return Err(Err(format!("Expected argument for {:?}", instr_str))),
-            };
+            if parts.next().is_none() {
+                return Err(Err(format!("Expected argument for {:?}", instr_str)));
+            }
+            // Operands may contain spaces, `[ ptr ]`, `base, U` or `label + 2`
+            let arg_str = s[instr_str.len()..].trim();
 
-            let arg = if arg_str.chars().all(|c| c.is_numeric()) {
-                match arg_str.parse::<u8>() {
-                    Ok(arg) => arg,
-                    Err(e) => {
-                        return Err(Err(format!(
-                            "Encountered an error parsing {} as u8: {}",
-                            arg_str, e
-                        )))
-                    }
+            // Register operands
+            match (
+                instr_str.to_uppercase().as_str(),
+                arg_str.to_uppercase().as_str(),
+            ) {
+                ("PUSH", "A") => return Ok(Instruction::PUSHA),
+                ("PUSH", "U") => return Ok(Instruction::PUSHU),
+                ("POP", "A") => return Ok(Instruction::POPA),
+                ("POP", "U") => return Ok(Instruction::POPU),
+                ("PUSH" | "POP", _) => {
+                    return Err(Err(format!(
+                        "Expected register A or U for {}, found {}",
+                        instr_str, arg_str
+                    )))
                 }
-            } else if arg_str.contains("0x") {
-                let arg_str = arg_str.trim_start_matches("0x");
-                match u8::from_str_radix(arg_str, 16) {
-                    Ok(num) => num,
-                    Err(e) => {
-                        return Err(Err(format!(
-                            "Encountered an error parsing {} as u8: {}",
-                            arg_str, e
-                        )))
-                    }
+                _ => (),
+            }
+
+            let mnemonic = instr_str.to_uppercase();
+            if let Some(instruction) = register_form(&mnemonic, arg_str)? {
+                return Ok(instruction);
+            }
+
+            let (mode, arg_str) = Mode::parse(arg_str);
+            let instruction_byte = match opcode(&mnemonic, mode) {
+                Some(byte) => byte,
+                None if mode == Mode::Absolute => {
+                    return Err(Err(format!("Unknown instruction {}", instr_str)))
                 }
-            } else if arg_str.contains("0b") {
-                let arg_str = arg_str.trim_start_matches("0b");
-                match u8::from_str_radix(arg_str, 2) {
-                    Ok(num) => num,
-                    Err(e) => {
-                        return Err(Err(format!(
-                            "Encountered an error parsing {} as u8: {}",
-                            arg_str, e
-                        )))
-                    }
+                None => {
+                    return Err(Err(format!(
+                        "{} does not support {:?} addressing",
+                        instr_str, mode
+                    )))
                 }
-            } else {
-                let instruction_byte = match instr_str.trim().to_uppercase().as_str() {
-                    "EXIT" => 0x11,
-                    "STA" => 0x02,
-                    "LDA" => 0x01,
-                    "SETV" => 0x05,
-                    "SETA" => 0x06,
-                    "STR" => 0x07,
-                    "LOAD" => 0x08,
-                    "JMP" => 0x0B,
-                    "JC" => 0x0C,
-                    "JZ" => 0x0E,
-                    "JO" => 0x0F,
-                    _ => 0,
-                };
-                return Err(Ok((instruction_byte, arg_str.to_owned())));
             };
-            match instr_str.trim().to_uppercase().as_str() {
-                "LDA" => Ok(Instruction::LDA(arg)),
-                "STA" => Ok(Instruction::STA(arg)),
-                "SETV" => Ok(Instruction::SETV(arg)),
-                "SETA" => Ok(Instruction::SETA(arg)),
-                "STR" => Ok(Instruction::STR(arg)),
-                "LOAD" => Ok(Instruction::LOAD(arg)),
-                "JMP" => Ok(Instruction::JMP(arg)),
-                "JC" => Ok(Instruction::JC(arg)),
-                "JZ" => Ok(Instruction::JZ(arg)),
-                "JO" => Ok(Instruction::JO(arg)),
-                "EXIT" => Ok(Instruction::EXIT(arg)),
-                _ => Err(Err(format!("Unknown instruction {}", instr_str))),
+
+            // SETV and EXIT take a byte, everything else a two byte address
+            let takes_value = matches!(mnemonic.as_str(), "SETV" | "EXIT");
+            let arg = match parse_number(arg_str) {
+                Ok(Some(arg)) => arg,
+                Ok(None) => return Err(Ok((vec![instruction_byte], arg_str.to_owned()))),
+                Err(e) => return Err(Err(e)),
+            };
+            if takes_value && arg > u8::MAX as u16 {
+                return Err(Err(format!(
+                    "{} takes a value from 0 to 255, found {}",
+                    instr_str, arg
+                )));
             }
+            Instruction::from_byte_and_arg(instruction_byte, arg)
+                .map_err(|_| Err(format!("Unknown instruction {}", instr_str)))
         } else {
             match s.trim().to_uppercase().as_str() {
                 "INC" => Ok(Instruction::INC),
@@ -253,6 +941,23 @@ impl TryFrom<String> for Instruction {
                 "NOP" => Ok(Instruction::NOP),
                 "OUT" => Ok(Instruction::OUT),
                 "CLN" => Ok(Instruction::CLN),
+                "IN" => Ok(Instruction::IN),
+                "RET" => Ok(Instruction::RET),
+                "CMP" => Ok(Instruction::CMP),
+                "MUL" => Ok(Instruction::MUL),
+                "DIV" => Ok(Instruction::DIV),
+                "MOD" => Ok(Instruction::MOD),
+                "EI" => Ok(Instruction::EI),
+                "DI" => Ok(Instruction::DI),
+                "IRET" => Ok(Instruction::IRET),
+                "AND" => Ok(Instruction::AND),
+                "OR" => Ok(Instruction::OR),
+                "XOR" => Ok(Instruction::XOR),
+                "NOT" => Ok(Instruction::NOT),
+                "SHL" => Ok(Instruction::SHL),
+                "SHR" => Ok(Instruction::SHR),
+                "ROL" => Ok(Instruction::ROL),
+                "ROR" => Ok(Instruction::ROR),
                 _ => Err(Err(format!("Expected argument for {:?}", s))),
             }
         }
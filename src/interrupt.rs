@@ -0,0 +1,54 @@
+use {
+    crate::IRQ_LINES,
+    std::sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+/// The pending interrupt requests of a cpu, one bit per line. Clones share the same lines, so
+/// devices and host threads can hold one to raise interrupts while the cpu runs.
+#[derive(Debug, Clone, Default)]
+pub struct IrqLines(Arc<AtomicU8>);
+impl IrqLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request an interrupt on `line`, serviced once interrupts are enabled.
+    ///
+    /// Panics if `line` is not below `IRQ_LINES`.
+    pub fn raise(&self, line: u8) {
+        assert!((line as usize) < IRQ_LINES, "no IRQ line {}", line);
+        self.0.fetch_or(1 << line, Ordering::SeqCst);
+    }
+
+    /// Withdraw a request on `line` that hasn't been serviced yet
+    pub fn lower(&self, line: u8) {
+        assert!((line as usize) < IRQ_LINES, "no IRQ line {}", line);
+        self.0.fetch_and(!(1 << line), Ordering::SeqCst);
+    }
+
+    /// The pending lines as a bit mask
+    pub fn pending(&self) -> u8 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Acknowledge the lowest pending line, which has the highest priority
+    pub(crate) fn take(&self) -> Option<u8> {
+        let mut pending = self.pending();
+        while pending != 0 {
+            let line = pending.trailing_zeros() as u8;
+            match self.0.compare_exchange(
+                pending,
+                pending & !(1 << line),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(line),
+                Err(current) => pending = current,
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Where `OUT` sends the accumulator
+pub trait Output {
+    fn output(&mut self, value: u8) -> std::io::Result<()>;
+}
+
+/// Prints each value on its own line on stdout
+pub struct Stdout;
+impl Output for Stdout {
+    fn output(&mut self, value: u8) -> std::io::Result<()> {
+        writeln!(std::io::stdout().lock(), "{}", value)
+    }
+}
+
+/// Writes each value on its own line to any writer, e.g. a `File`
+pub struct Writer<W: Write>(pub W);
+impl<W: Write> Output for Writer<W> {
+    fn output(&mut self, value: u8) -> std::io::Result<()> {
+        writeln!(self.0, "{}", value)
+    }
+}
+
+/// Collects values in memory. Clones share the same buffer, so keep one to read what a `Cpu`
+/// wrote through another.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear()
+    }
+}
+impl Output for Buffer {
+    fn output(&mut self, value: u8) -> std::io::Result<()> {
+        self.0.lock().unwrap().push(value);
+        Ok(())
+    }
+}
+
+/// Any `FnMut(u8)` can be used as a callback
+impl<F: FnMut(u8)> Output for F {
+    fn output(&mut self, value: u8) -> std::io::Result<()> {
+        self(value);
+        Ok(())
+    }
+}
+
+/// Where `IN` reads from. `Ok(None)` means the input is exhausted.
+pub trait Input {
+    fn input(&mut self) -> std::io::Result<Option<u8>>;
+}
+
+/// Reads one value per line from stdin
+pub struct Stdin;
+impl Input for Stdin {
+    fn input(&mut self) -> std::io::Result<Option<u8>> {
+        Reader(std::io::stdin().lock()).input()
+    }
+}
+
+/// Reads one value per line from any buffered reader, e.g. a `BufReader<File>`.
+/// Values are decimal, or hex/binary with a `0x`/`0b` prefix; blank lines are skipped.
+pub struct Reader<R: BufRead>(pub R);
+impl<R: BufRead> Input for Reader<R> {
+    fn input(&mut self) -> std::io::Result<Option<u8>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.0.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let value = line.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let parsed = if let Some(hex) = value.strip_prefix("0x") {
+                u8::from_str_radix(hex, 16)
+            } else if let Some(bin) = value.strip_prefix("0b") {
+                u8::from_str_radix(bin, 2)
+            } else {
+                value.parse()
+            };
+            return match parsed {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} is not a byte: {}", value, e),
+                )),
+            };
+        }
+    }
+}
+
+/// A fixed script of values, for tests
+#[derive(Clone, Default)]
+pub struct Queue(pub VecDeque<u8>);
+impl Queue {
+    pub fn new(values: &[u8]) -> Self {
+        Self(values.iter().copied().collect())
+    }
+}
+impl Input for Queue {
+    fn input(&mut self) -> std::io::Result<Option<u8>> {
+        Ok(self.0.pop_front())
+    }
+}
+
+/// Any `FnMut() -> Option<u8>` can be used as a callback
+impl<F: FnMut() -> Option<u8>> Input for F {
+    fn input(&mut self) -> std::io::Result<Option<u8>> {
+        Ok(self())
+    }
+}
@@ -0,0 +1,43 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// `byte name;`
+    Declare(String, usize),
+    /// `name = expr;`
+    Assign(String, Expression, usize),
+    /// `print expr;`
+    Print(Expression),
+    /// `while cond { ... }`
+    While(Condition, Vec<Statement>),
+    /// `if cond { ... } else { ... }`
+    If(Condition, Vec<Statement>, Vec<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Variable(String, usize),
+    Literal(u8),
+    Binary(Box<Expression>, BinaryOp, Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub lhs: Expression,
+    pub op: Comparison,
+    pub rhs: Expression,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+}
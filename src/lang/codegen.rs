@@ -0,0 +1,314 @@
+use crate::{
+    lang::{
+        ast::{BinaryOp, Comparison, Condition, Expression, Statement},
+        error::CompileError,
+    },
+    MEMORY_SIZE,
+};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Literal(u8),
+    Variable(usize),
+    Temporary(usize),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Label(String),
+    Op(&'static str, Option<Operand>),
+}
+
+/// Lowers a parsed program to assembler source.
+///
+/// Variables and temporaries live in a data area placed directly after the code. Arithmetic
+/// saturates at 0 and 255, and comparisons are a `CMP` followed by the matching conditional jump.
+pub struct CodeGenerator {
+    lines: Vec<Line>,
+    variables: HashMap<String, usize>,
+    variable_names: Vec<String>,
+    temporaries: usize,
+    max_temporaries: usize,
+    next_label: usize,
+}
+impl CodeGenerator {
+    pub fn new() -> Self {
+        Self {
+            lines: vec![],
+            variables: HashMap::new(),
+            variable_names: vec![],
+            temporaries: 0,
+            max_temporaries: 0,
+            next_label: 0,
+        }
+    }
+
+    pub fn generate(mut self, program: &[Statement]) -> Result<String, CompileError> {
+        for statement in program {
+            self.statement(statement)?;
+        }
+        self.op("EXIT", Some(Operand::Literal(0)));
+
+        // A label on the first byte resolves to 0, and a jump to 0 resumes at 1
+        if let Some(Line::Label(_)) = self.lines.first() {
+            self.lines.insert(0, Line::Op("NOP", None));
+        }
+
+        let code_size: usize = self
+            .lines
+            .iter()
+            .map(|line| match line {
+                Line::Label(_) => 0,
+                Line::Op(_, None) => 1,
+                // SETV and EXIT take a byte, everything else a two byte address
+                Line::Op("SETV" | "EXIT", Some(_)) => 2,
+                Line::Op(_, Some(_)) => 3,
+            })
+            .sum();
+        let total = code_size + self.variable_names.len() + self.max_temporaries;
+        if total >= MEMORY_SIZE {
+            return Err(CompileError::OutOfMemory(total));
+        }
+
+        Ok(self.render(code_size))
+    }
+
+    fn render(&self, data_base: usize) -> String {
+        let variable_addr = |idx: usize| data_base + idx;
+        let temporary_addr = |idx: usize| data_base + self.variable_names.len() + idx;
+
+        let mut out = Vec::new();
+        for (idx, name) in self.variable_names.iter().enumerate() {
+            out.push(format!(";; {} -> 0x{:04X}", name, variable_addr(idx)));
+        }
+        if !out.is_empty() {
+            out.push(String::new());
+        }
+
+        for line in self.lines.iter() {
+            let rendered = match line {
+                Line::Label(label) => format!("{}:", label),
+                Line::Op(mnemonic, None) => format!("    {}", mnemonic),
+                Line::Op(mnemonic, Some(operand)) => match operand {
+                    Operand::Literal(n) => format!("    {} {}", mnemonic, n),
+                    Operand::Label(label) => format!("    {} {}", mnemonic, label),
+                    Operand::Variable(idx) => format!(
+                        "    {} 0x{:04X} ;; {}",
+                        mnemonic,
+                        variable_addr(*idx),
+                        self.variable_names[*idx]
+                    ),
+                    Operand::Temporary(idx) => format!(
+                        "    {} 0x{:04X} ;; tmp{}",
+                        mnemonic,
+                        temporary_addr(*idx),
+                        idx
+                    ),
+                },
+            };
+            out.push(rendered);
+        }
+
+        out.join("\n") + "\n"
+    }
+
+    fn op(&mut self, mnemonic: &'static str, operand: Option<Operand>) {
+        self.lines.push(Line::Op(mnemonic, operand));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(Line::Label(label.to_owned()));
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.next_label += 1;
+        format!("{}_{}", kind, self.next_label)
+    }
+
+    fn allocate_temporary(&mut self) -> Operand {
+        let idx = self.temporaries;
+        self.temporaries += 1;
+        self.max_temporaries = self.max_temporaries.max(self.temporaries);
+        Operand::Temporary(idx)
+    }
+
+    fn release_temporary(&mut self, operand: Operand) {
+        if let Operand::Temporary(_) = operand {
+            self.temporaries -= 1;
+        }
+    }
+
+    fn variable(&self, name: &str, line: usize) -> Result<Operand, CompileError> {
+        match self.variables.get(name) {
+            Some(idx) => Ok(Operand::Variable(*idx)),
+            None => Err(CompileError::UndeclaredVariable(name.to_owned(), line)),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::Declare(name, line) => {
+                if self.variables.contains_key(name) {
+                    return Err(CompileError::Redeclared(name.to_owned(), *line));
+                }
+                self.variables
+                    .insert(name.to_owned(), self.variable_names.len());
+                self.variable_names.push(name.to_owned());
+                let variable = self.variable(name, *line)?;
+                self.op("SETV", Some(Operand::Literal(0)));
+                self.op("STR", Some(variable));
+            }
+            Statement::Assign(name, value, line) => {
+                let variable = self.variable(name, *line)?;
+                self.expression(value)?;
+                self.op("STA", Some(variable));
+            }
+            Statement::Print(value) => {
+                self.expression(value)?;
+                self.op("OUT", None);
+            }
+            Statement::While(condition, body) => {
+                let head = self.new_label("while");
+                let done = self.new_label("done");
+                self.label(&head);
+                self.branch(condition, &done)?;
+                for statement in body {
+                    self.statement(statement)?;
+                }
+                self.op("JMP", Some(Operand::Label(head)));
+                self.label(&done);
+            }
+            Statement::If(condition, then, otherwise) => {
+                let otherwise_label = self.new_label("else");
+                self.branch(condition, &otherwise_label)?;
+                for statement in then {
+                    self.statement(statement)?;
+                }
+                if otherwise.is_empty() {
+                    self.label(&otherwise_label);
+                } else {
+                    let done = self.new_label("done");
+                    self.op("JMP", Some(Operand::Label(done.clone())));
+                    self.label(&otherwise_label);
+                    for statement in otherwise {
+                        self.statement(statement)?;
+                    }
+                    self.label(&done);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fall through if `condition` holds, otherwise jump to `on_false`
+    fn branch(&mut self, condition: &Condition, on_false: &str) -> Result<(), CompileError> {
+        let rhs = self.operand(&condition.rhs)?;
+        let lhs = self.operand(&condition.lhs)?;
+
+        let jump = match condition.op {
+            Comparison::Lt => "JGE",
+            Comparison::Gt => "JLE",
+            Comparison::Le => "JGT",
+            Comparison::Ge => "JLT",
+            Comparison::Eq => "JNZ",
+            Comparison::Ne => "JZ",
+        };
+        self.compare(&lhs, &rhs);
+        self.op(jump, Some(Operand::Label(on_false.to_owned())));
+
+        self.release_temporary(lhs);
+        self.release_temporary(rhs);
+        Ok(())
+    }
+
+    /// Compare `lhs` with `rhs`, for a conditional jump to follow
+    fn compare(&mut self, lhs: &Operand, rhs: &Operand) {
+        match lhs {
+            Operand::Literal(n) => {
+                self.op("SETV", Some(Operand::Literal(*n)));
+                self.op("CLN", None);
+            }
+            slot => self.op("LDA", Some(slot.clone())),
+        }
+        self.load_user(rhs);
+        self.op("CMP", None);
+    }
+
+    fn load_user(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Literal(n) => self.op("SETV", Some(Operand::Literal(*n))),
+            slot => self.op("LOAD", Some(slot.clone())),
+        }
+    }
+
+    /// An operand for `value`, spilling it to a temporary unless it is a variable or literal
+    fn operand(&mut self, value: &Expression) -> Result<Operand, CompileError> {
+        match value {
+            Expression::Literal(n) => Ok(Operand::Literal(*n)),
+            Expression::Variable(name, line) => self.variable(name, *line),
+            Expression::Binary(..) => {
+                self.expression(value)?;
+                let temporary = self.allocate_temporary();
+                self.op("STA", Some(temporary.clone()));
+                Ok(temporary)
+            }
+        }
+    }
+
+    /// Evaluate `value` into the accumulator
+    fn expression(&mut self, value: &Expression) -> Result<(), CompileError> {
+        match value {
+            Expression::Literal(n) => {
+                self.op("SETV", Some(Operand::Literal(*n)));
+                self.op("CLN", None);
+            }
+            Expression::Variable(name, line) => {
+                let variable = self.variable(name, *line)?;
+                self.op("LDA", Some(variable));
+            }
+            Expression::Binary(lhs, op, rhs) => {
+                let rhs = self.operand(rhs)?;
+                self.expression(lhs)?;
+                match op {
+                    BinaryOp::Add => self.saturating_add(&rhs),
+                    BinaryOp::Sub => self.saturating_sub(&rhs),
+                }
+                self.release_temporary(rhs);
+            }
+        }
+        Ok(())
+    }
+
+    fn saturating_add(&mut self, rhs: &Operand) {
+        let saturate = self.new_label("saturate");
+        let done = self.new_label("done");
+        self.load_user(rhs);
+        self.op("ADD", None);
+        self.op("JO", Some(Operand::Label(saturate.clone())));
+        self.op("JMP", Some(Operand::Label(done.clone())));
+        self.label(&saturate);
+        self.op("SETV", Some(Operand::Literal(255)));
+        self.op("CLN", None);
+        self.label(&done);
+    }
+
+    fn saturating_sub(&mut self, rhs: &Operand) {
+        let saturate = self.new_label("saturate");
+        let done = self.new_label("done");
+        let lhs = self.allocate_temporary();
+        self.op("STA", Some(lhs.clone()));
+        self.compare(&lhs, rhs);
+        self.op("JLT", Some(Operand::Label(saturate.clone())));
+        self.op("LDA", Some(lhs.clone()));
+        self.load_user(rhs);
+        self.op("SUB", None);
+        self.op("JMP", Some(Operand::Label(done.clone())));
+        self.label(&saturate);
+        self.op("SETV", Some(Operand::Literal(0)));
+        self.op("CLN", None);
+        self.label(&done);
+        self.release_temporary(lhs);
+    }
+}
@@ -0,0 +1,26 @@
+use crate::lang::{codegen::CodeGenerator, error::CompileError, lexer, parser::Parser};
+use std::{fs::File, io::Read};
+
+pub struct Compiler {
+    input: File,
+}
+impl Compiler {
+    pub fn new(file_handle: File) -> Self {
+        Self { input: file_handle }
+    }
+
+    /// Compile the input to assembler source
+    pub fn compile(&mut self) -> Result<String, CompileError> {
+        let mut source = String::new();
+        if let Err(e) = self.input.read_to_string(&mut source) {
+            return Err(CompileError::IOError(e));
+        }
+        compile_source(&source)
+    }
+}
+
+pub fn compile_source(source: &str) -> Result<String, CompileError> {
+    let tokens = lexer::tokenize(source)?;
+    let program = Parser::new(tokens).parse()?;
+    CodeGenerator::new().generate(&program)
+}
@@ -0,0 +1,45 @@
+use std::io::Error;
+
+#[derive(Debug)]
+pub enum CompileError {
+    IOError(Error),
+    UnexpectedCharacter(char, usize),
+    InvalidNumber(String, usize),
+    UnexpectedToken(String, String, usize),
+    UnexpectedEof(String),
+    UndeclaredVariable(String, usize),
+    Redeclared(String, usize),
+    OutOfMemory(usize),
+}
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::IOError(e) => write!(f, "failed to read input: {}", e),
+            CompileError::UnexpectedCharacter(c, line) => {
+                write!(f, "line {}: unexpected character {:?}", line, c)
+            }
+            CompileError::InvalidNumber(s, line) => {
+                write!(f, "line {}: {:?} is not a valid byte", line, s)
+            }
+            CompileError::UnexpectedToken(expected, found, line) => {
+                write!(f, "line {}: expected {}, found {}", line, expected, found)
+            }
+            CompileError::UnexpectedEof(expected) => {
+                write!(f, "expected {}, found end of input", expected)
+            }
+            CompileError::UndeclaredVariable(name, line) => {
+                write!(f, "line {}: use of undeclared variable {:?}", line, name)
+            }
+            CompileError::Redeclared(name, line) => {
+                write!(f, "line {}: variable {:?} is already declared", line, name)
+            }
+            CompileError::OutOfMemory(nbytes) => write!(
+                f,
+                "program needs {} bytes of code and data, but memory only holds {}",
+                nbytes,
+                crate::MEMORY_SIZE - 1
+            ),
+        }
+    }
+}
+impl std::error::Error for CompileError {}
@@ -0,0 +1,123 @@
+use crate::lang::error::CompileError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Number(u8),
+    Semicolon,
+    Assign,
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+}
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{:?}", name),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Semicolon => write!(f, "';'"),
+            Token::Assign => write!(f, "'='"),
+            Token::LBrace => write!(f, "'{{'"),
+            Token::RBrace => write!(f, "'}}'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Plus => write!(f, "'+'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Lt => write!(f, "'<'"),
+            Token::Gt => write!(f, "'>'"),
+            Token::Le => write!(f, "'<='"),
+            Token::Ge => write!(f, "'>='"),
+            Token::Eq => write!(f, "'=='"),
+            Token::Ne => write!(f, "'!='"),
+        }
+    }
+}
+
+/// Split `source` into tokens, each paired with the (1-based) line it starts on
+pub fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                // Line comment
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            ';' => Token::Semicolon,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '<' | '>' | '=' | '!' => {
+                let followed_by_eq = chars.peek() == Some(&'=');
+                if followed_by_eq {
+                    chars.next();
+                }
+                match (c, followed_by_eq) {
+                    ('<', false) => Token::Lt,
+                    ('<', true) => Token::Le,
+                    ('>', false) => Token::Gt,
+                    ('>', true) => Token::Ge,
+                    ('=', false) => Token::Assign,
+                    ('=', true) => Token::Eq,
+                    ('!', true) => Token::Ne,
+                    _ => return Err(CompileError::UnexpectedCharacter(c, line)),
+                }
+            }
+            c if c.is_ascii_digit() => {
+                let mut literal = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                    literal.push(*c);
+                    chars.next();
+                }
+                let parsed = if let Some(hex) = literal.strip_prefix("0x") {
+                    u8::from_str_radix(hex, 16)
+                } else if let Some(bin) = literal.strip_prefix("0b") {
+                    u8::from_str_radix(bin, 2)
+                } else {
+                    literal.parse()
+                };
+                match parsed {
+                    Ok(n) => Token::Number(n),
+                    Err(_) => return Err(CompileError::InvalidNumber(literal, line)),
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    ident.push(*c);
+                    chars.next();
+                }
+                Token::Ident(ident)
+            }
+            c => return Err(CompileError::UnexpectedCharacter(c, line)),
+        };
+        tokens.push((token, line));
+    }
+
+    Ok(tokens)
+}
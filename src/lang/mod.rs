@@ -0,0 +1,119 @@
+pub(crate) mod ast;
+pub(crate) mod codegen;
+pub mod compiler;
+pub mod error;
+pub(crate) mod lexer;
+pub(crate) mod parser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::assembler::{assemble_str, Assembler},
+        cpu::{Cpu, Stop},
+        io::Buffer,
+    };
+    use std::fs::File;
+
+    fn run_collecting_output(image: &[u8]) -> (Vec<u8>, Stop) {
+        let output = Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(image)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        let report = cpu.run_for(100_000);
+        (output.contents(), report.stop)
+    }
+
+    fn compile_and_run(source: &str) -> Vec<u8> {
+        let assembly = compiler::compile_source(source).unwrap();
+        let image = assemble_str(&assembly);
+        let (output, result) = run_collecting_output(&image);
+        assert_eq!(result, Stop::Exit(0), "{}", assembly);
+        output
+    }
+
+    #[test]
+    fn test_compile_fib() {
+        let file_handle = File::open("./tests/fib.ln").unwrap();
+        let assembly = compiler::Compiler::new(file_handle).compile().unwrap();
+        let (output, result) = run_collecting_output(&assemble_str(&assembly));
+        assert_eq!(result, Stop::Exit(0));
+
+        let file_handle = File::open("./tests/fib.as").unwrap();
+        let mut assembler = Assembler::new(file_handle);
+        assembler.parse().unwrap();
+        let (expected, result) = run_collecting_output(&assembler.get_output());
+        assert_eq!(result, Stop::Exit(1));
+
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let cases = [
+            ("a < b", 1),
+            ("a > b", 0),
+            ("a <= 7", 1),
+            ("b >= 201", 0),
+            ("a + 193 == b", 1),
+            ("0 != a - 7", 0),
+            ("255 < 0", 0),
+            ("0 < 255", 1),
+            ("b == b", 1),
+        ];
+        for (condition, expected) in cases.iter() {
+            let source = format!(
+                "byte a; byte b; a = 7; b = 200; if {} {{ print 1; }} else {{ print 0; }}",
+                condition
+            );
+            let output = compile_and_run(&source);
+            assert_eq!(output, vec![*expected], "{}", condition);
+        }
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        let source = "
+            byte a;
+            a = 250;
+            print a + 10;
+            print a - 251;
+            print (a - 200) - (a - 240);
+            print 0 - 0;
+            print 0 + 0;
+        ";
+        assert_eq!(compile_and_run(source), vec![255, 0, 40, 0, 0]);
+    }
+
+    #[test]
+    fn test_while_countdown() {
+        let source = "
+            // count down to zero
+            byte i;
+            i = 3;
+            while i != 0 {
+                print i;
+                i = i - 1;
+            }
+        ";
+        assert_eq!(compile_and_run(source), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        use error::CompileError;
+
+        let result = compiler::compile_source("byte x;\ny = 1;");
+        assert!(matches!(result, Err(CompileError::UndeclaredVariable(name, 2)) if name == "y"));
+        let result = compiler::compile_source("byte x;\nbyte x;");
+        assert!(matches!(result, Err(CompileError::Redeclared(name, 2)) if name == "x"));
+        let result = compiler::compile_source("byte x;\nx = 256;");
+        assert!(matches!(result, Err(CompileError::InvalidNumber(_, 2))));
+        let result = compiler::compile_source("byte x;\nwhile x < 3 { print x;");
+        assert!(matches!(result, Err(CompileError::UnexpectedEof(_))));
+        let result = compiler::compile_source("byte x\nx = 1;");
+        assert!(matches!(result, Err(CompileError::UnexpectedToken(_, _, 2))));
+    }
+}
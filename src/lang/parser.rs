@@ -0,0 +1,171 @@
+use crate::lang::{
+    ast::{BinaryOp, Comparison, Condition, Expression, Statement},
+    error::CompileError,
+    lexer::Token,
+};
+
+pub struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+impl Parser {
+    pub fn new(tokens: Vec<(Token, usize)>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Statement>, CompileError> {
+        let mut program = Vec::new();
+        while self.peek().is_some() {
+            program.push(self.statement()?);
+        }
+        Ok(program)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(Token, usize), CompileError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(CompileError::UnexpectedEof(expected.to_owned())),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), CompileError> {
+        let description = expected.to_string();
+        match self.next(&description)? {
+            (token, _) if token == expected => Ok(()),
+            (token, line) => Err(CompileError::UnexpectedToken(
+                description,
+                token.to_string(),
+                line,
+            )),
+        }
+    }
+
+    fn ident(&mut self) -> Result<(String, usize), CompileError> {
+        match self.next("an identifier")? {
+            (Token::Ident(name), line) => Ok((name, line)),
+            (token, line) => Err(CompileError::UnexpectedToken(
+                "an identifier".to_owned(),
+                token.to_string(),
+                line,
+            )),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let (keyword, line) = self.ident()?;
+        match keyword.as_str() {
+            "byte" => {
+                let (name, line) = self.ident()?;
+                self.expect(Token::Semicolon)?;
+                Ok(Statement::Declare(name, line))
+            }
+            "print" => {
+                let value = self.expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(Statement::Print(value))
+            }
+            "while" => {
+                let condition = self.condition()?;
+                let body = self.block()?;
+                Ok(Statement::While(condition, body))
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let then = self.block()?;
+                let otherwise = if self.peek() == Some(&Token::Ident("else".to_owned())) {
+                    self.pos += 1;
+                    self.block()?
+                } else {
+                    vec![]
+                };
+                Ok(Statement::If(condition, then, otherwise))
+            }
+            _ => {
+                self.expect(Token::Assign)?;
+                let value = self.expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(Statement::Assign(keyword, value, line))
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.expect(Token::LBrace)?;
+        let mut statements = Vec::new();
+        while self.peek() != Some(&Token::RBrace) {
+            if self.peek().is_none() {
+                return Err(CompileError::UnexpectedEof("'}'".to_owned()));
+            }
+            statements.push(self.statement()?);
+        }
+        self.pos += 1;
+        Ok(statements)
+    }
+
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        let lhs = self.expression()?;
+        let op = match self.next("a comparison")? {
+            (Token::Lt, _) => Comparison::Lt,
+            (Token::Gt, _) => Comparison::Gt,
+            (Token::Le, _) => Comparison::Le,
+            (Token::Ge, _) => Comparison::Ge,
+            (Token::Eq, _) => Comparison::Eq,
+            (Token::Ne, _) => Comparison::Ne,
+            (token, line) => {
+                return Err(CompileError::UnexpectedToken(
+                    "a comparison".to_owned(),
+                    token.to_string(),
+                    line,
+                ))
+            }
+        };
+        let rhs = self.expression()?;
+        Ok(Condition { lhs, op, rhs })
+    }
+
+    fn expression(&mut self) -> Result<Expression, CompileError> {
+        let mut lhs = self.atom()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.atom()?;
+            lhs = Expression::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expression, CompileError> {
+        let line = self.line();
+        match self.next("an expression")? {
+            (Token::Ident(name), line) => Ok(Expression::Variable(name, line)),
+            (Token::Number(n), _) => Ok(Expression::Literal(n)),
+            (Token::LParen, _) => {
+                let inner = self.expression()?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            (token, _) => Err(CompileError::UnexpectedToken(
+                "an expression".to_owned(),
+                token.to_string(),
+                line,
+            )),
+        }
+    }
+}
@@ -0,0 +1,1228 @@
+//! An emulator for a small 8-bit accumulator machine with a 16-bit address bus, with an
+//! assembler, disassembler, debugger and a compiler for the `.ln` language.
+//!
+//! ```
+//! use cpu::{Assembler, Cpu, CpuError};
+//!
+//! let program = Assembler::assemble("    setv 41\n    cln\n    inc\n    exit 0\n").unwrap();
+//! let mut cpu = Cpu::builder().program(&program).build().unwrap();
+//! assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+//! assert_eq!(cpu.accumulator, 42);
+//! ```
+#![allow(clippy::upper_case_acronyms)]
+
+/// The whole 16-bit address space
+pub const MEMORY_SIZE: usize = 0x10000;
+/// Bytes at the top of memory reserved for the stack
+pub const STACK_SIZE: usize = 256;
+/// Interrupt request lines, numbered from 0 which has the highest priority
+pub const IRQ_LINES: usize = 8;
+/// General-purpose registers, R0 to R7
+pub const REGISTERS: usize = 8;
+/// The two byte handler addresses for each IRQ line, directly below the stack
+pub const VECTOR_TABLE: usize = MEMORY_SIZE - STACK_SIZE - 2 * IRQ_LINES;
+/// Memory of the original 8-bit machine, emulated by `AddressMode::Narrow`
+pub const NARROW_MEMORY_SIZE: usize = 255;
+/// Stack of the original 8-bit machine
+pub const NARROW_STACK_SIZE: usize = 32;
+pub const DEBUG: bool = false;
+
+pub mod asm;
+pub mod cpu;
+pub mod debugger;
+pub mod error;
+pub mod flags;
+pub mod instruction;
+pub mod interrupt;
+pub mod io;
+pub mod lang;
+pub mod memory;
+pub mod trace;
+
+pub use crate::{
+    asm::{
+        assembler::Assembler,
+        disassembler::Disassembler,
+        error::{AssemblerError, DisassemblerError, JitError},
+    },
+    cpu::{AddressMode, Cpu, CpuBuilder, Limits, RunReport, Stop},
+    debugger::Debugger,
+    error::CpuError,
+    flags::Flags,
+    instruction::{GeneralRegister, Instruction, Register},
+    interrupt::IrqLines,
+    io::{Input, Output},
+    lang::{compiler::Compiler, error::CompileError},
+    memory::{Device, Memory},
+    trace::{TraceFormat, TraceRecord, TraceWriter, Tracer},
+};
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    pub const NOP: u8 = 0x00;
+    pub const LDA: u8 = 0x01;
+    pub const STA: u8 = 0x02;
+    pub const INC: u8 = 0x03;
+    pub const DEC: u8 = 0x04;
+    pub const SETV: u8 = 0x05;
+    pub const SETA: u8 = 0x06;
+    pub const STR: u8 = 0x07;
+    pub const LOAD: u8 = 0x08;
+    pub const ADD: u8 = 0x09;
+    pub const SUB: u8 = 0x0A;
+    pub const JMP: u8 = 0x0B;
+    pub const JC: u8 = 0x0C;
+    pub const JZ: u8 = 0x0E;
+    pub const JNZ: u8 = 0x0D;
+    pub const JO: u8 = 0x0F;
+    pub const OUT: u8 = 0x10;
+    pub const EXIT: u8 = 0x11;
+    pub const CLN: u8 = 0x12;
+    pub const IN: u8 = 0x13;
+    pub const PUSHA: u8 = 0x14;
+    pub const PUSHU: u8 = 0x15;
+    pub const POPA: u8 = 0x16;
+    pub const POPU: u8 = 0x17;
+    pub const CALL: u8 = 0x18;
+    pub const RET: u8 = 0x19;
+    pub const LDAI: u8 = 0x1A;
+    pub const STAI: u8 = 0x1B;
+    pub const SETAI: u8 = 0x1C;
+    pub const STRI: u8 = 0x1D;
+    pub const LOADI: u8 = 0x1E;
+    pub const LDAX: u8 = 0x1F;
+    pub const STAX: u8 = 0x20;
+    pub const SETAX: u8 = 0x21;
+    pub const STRX: u8 = 0x22;
+    pub const LOADX: u8 = 0x23;
+    pub const AND: u8 = 0x24;
+    pub const OR: u8 = 0x25;
+    pub const XOR: u8 = 0x26;
+    pub const NOT: u8 = 0x27;
+    pub const SHL: u8 = 0x28;
+    pub const SHR: u8 = 0x29;
+    pub const ROL: u8 = 0x2A;
+    pub const ROR: u8 = 0x2B;
+    pub const JNO: u8 = 0x2C;
+    pub const JNC: u8 = 0x2D;
+    pub const JN: u8 = 0x2E;
+    pub const JNN: u8 = 0x2F;
+    pub const JLT: u8 = 0x30;
+    pub const JGE: u8 = 0x31;
+    pub const JGT: u8 = 0x32;
+    pub const JLE: u8 = 0x33;
+    pub const CMP: u8 = 0x34;
+    pub const MUL: u8 = 0x35;
+    pub const DIV: u8 = 0x36;
+    pub const MOD: u8 = 0x37;
+    pub const EI: u8 = 0x38;
+    pub const DI: u8 = 0x39;
+    pub const IRET: u8 = 0x3A;
+    pub const MOV: u8 = 0x3B;
+    pub const MOVI: u8 = 0x3C;
+    pub const ADDR: u8 = 0x3D;
+    pub const SUBR: u8 = 0x3E;
+    pub const CMPR: u8 = 0x3F;
+    pub const ANDR: u8 = 0x40;
+    pub const ORR: u8 = 0x41;
+    pub const XORR: u8 = 0x42;
+    pub const INCR: u8 = 0x43;
+    pub const DECR: u8 = 0x44;
+    pub const JCS: u8 = 0x45;
+
+    #[test]
+    fn test_acc_ops() {
+        let mut cpu = cpu::Cpu::new();
+        let output = io::Buffer::new();
+        cpu.set_output(output.clone());
+        cpu.memory = memory::Memory::new_with_instructions(&[
+            SETV, 10, STR, 0x40, 0, LDA, 0x40, 0, SETV, 1, ADD, OUT, EXIT, 0,
+        ]);
+
+        match cpu.run() {
+            Err(error::CpuError::Exit(code)) => assert_eq!(code, 0),
+            Err(e) => panic!("{:?}", e),
+            _ => (),
+        };
+        assert_eq!(11, cpu.accumulator);
+        assert_eq!(output.contents(), vec![11]);
+    }
+
+    #[test]
+    fn test_load_store() {
+        let mut cpu = cpu::Cpu::new();
+        let output = io::Buffer::new();
+        cpu.set_output(output.clone());
+        cpu.memory = memory::Memory::new_with_instructions(&[
+            OUT, // -> 0
+            SETV, 10, STR, 0x40, 0, LDA, 0x40, 0, OUT, // -> 10
+            INC, STA, 0x40, 0, LOAD, 0x40, 0, CLN, OUT, // -> 11
+            EXIT, 0,
+        ]);
+        assert_eq!(cpu.run(), Err(error::CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![0, 10, 11]);
+    }
+
+    #[test]
+    fn test_fib() {
+        let mut cpu = cpu::Cpu::new();
+        let output = io::Buffer::new();
+        cpu.set_output(output.clone());
+        cpu.memory = memory::Memory::new_with_instructions(&[
+            // init:
+            SETV, 0, STR, 0x40, 0, // x = 0
+            STR, 0x42, 0, // z = 0
+            SETV, 1, STR, 0x41, 0, // y = 1
+            // loop
+
+            // print z
+            LDA, 0x42, 0, OUT, // z = x + y
+            LDA, 0x40, 0, // load x into acc
+            LOAD, 0x41, 0,   // load y into usr
+            ADD, // add y to acc (x) -> acc = x + y
+            JO, 44, 0, // exit if overflow
+            STA, 0x42, 0, // store acc in z
+            // x = y
+            LDA, 0x41, 0, // load y into acc
+            STA, 0x40, 0, // store y in x
+            // y = z
+            LDA, 0x42, 0, // load z into acc
+            STA, 0x41, 0, // store z in y
+            // while z < 255
+            JMP, 12, 0, // reenter the loop otherwise
+            // exit_good:
+            EXIT, 1,
+        ]);
+
+        match cpu.run_for(10_000).stop {
+            Stop::Exit(1) => eprintln!("Exited correctly"),
+            Stop::Exit(code) => panic!("Exited with code {}", code),
+            stop => panic!("{:?}", stop),
+        };
+        assert_eq!(
+            output.contents(),
+            vec![0, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233]
+        );
+    }
+
+    #[test]
+    fn test_registers() {
+        // test_fib without spilling to memory
+        let source = "
+    MOV R0, 0
+    MOV R1, 1
+loop:
+    MOV A, R0
+    OUT
+    MOV R2, R0
+    ADD R2, R1
+    JO done
+    MOV R0, R1
+    MOV R1, R2
+    JMP loop
+done:
+    EXIT 1
+";
+        let program = Assembler::assemble(source).unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(1)));
+        assert_eq!(
+            output.contents(),
+            vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144]
+        );
+        assert_eq!(cpu.registers[..3], [144, 233, 121]);
+
+        // The accumulator forms still work next to the register forms
+        let mut cpu = Cpu::builder()
+            .program(&[
+                MOVI, 0x03, 7, MOV, 0x93, ADD, SUBR, 0x38, DECR, 0x03, EXIT, 0,
+            ])
+            .accumulator(5)
+            .register(Register::r(4).unwrap(), 9)
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!((cpu.accumulator, cpu.user), (12, 7));
+        // R3 = 7 - 12, borrowing, then decremented
+        assert_eq!(cpu.registers, [0, 0, 0, 250, 9, 0, 0, 0]);
+        assert!(cpu.flags.negative);
+
+        let mut cpu = Cpu::builder().program(&[MOV, 0xA0]).build().unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::MalformedInput(MOV)));
+
+        // Only R0 to R7 exist
+        assert_eq!(Register::r(8), None);
+        assert_eq!(Register::from_code(0xA), None);
+        assert_eq!(Register::parse("r8"), None);
+        let r7 = Register::parse("r7").unwrap();
+        assert_eq!((r7, r7.code()), (Register::r(7).unwrap(), 7));
+        assert!(matches!(r7, Register::R(general) if general.index() == 7));
+    }
+
+    #[test]
+    fn test_compat_mode() {
+        // The 8-bit encoding of test_fib, with one byte addresses
+        let program = [
+            SETV, 0, STR, 0x40, STR, 0x42, SETV, 1, STR, 0x41, LDA, 0x42, OUT, LDA, 0x40, LOAD,
+            0x41, ADD, JO, 31, STA, 0x42, LDA, 0x41, STA, 0x40, LDA, 0x42, STA, 0x41, JMP, 9, EXIT,
+            1,
+        ];
+        let mut cpu = Cpu::builder()
+            .mode(AddressMode::Narrow)
+            .program(&program)
+            .output(io::Buffer::new())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(1)));
+        assert_eq!(cpu.memory.get(0x40), Ok(144));
+        assert_eq!(cpu.memory.get(0x41), Ok(233));
+
+        // Return addresses are one byte, on a stack at the top of the old 255 byte memory
+        let mut cpu = Cpu::builder()
+            .mode(AddressMode::Narrow)
+            .program(&[CALL, 4, EXIT, 0, NOP, SETV, 9, RET])
+            .build()
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip, 5);
+        assert_eq!(cpu.sp as usize, NARROW_MEMORY_SIZE - 2);
+        assert_eq!(cpu.memory.get(NARROW_MEMORY_SIZE as u16 - 1), Ok(1));
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(cpu.user, 9);
+
+        // The old memory ends at 0xFE, even though the bus is wider
+        let mut cpu = Cpu::builder()
+            .mode(AddressMode::Narrow)
+            .program(&[STA, 0xFF])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::MemoryError(0xFF)));
+        let cpu = Cpu::builder()
+            .mode(AddressMode::Narrow)
+            .load(0xF0, &[0; 0x10])
+            .build();
+        assert!(matches!(cpu, Err(CpuError::MemoryError(_))));
+
+        // An old binary from disk, where JC jumps on overflow alone
+        let path = std::env::temp_dir().join(format!("cpu_test_{}_compat.bin", std::process::id()));
+        let program = [SETV, 100, CLN, SETV, 200, ADD, JC, 9, EXIT, 1, EXIT, 2];
+        std::fs::write(&path, program).unwrap();
+        let result = Cpu::from_binary(&path, AddressMode::Narrow).map(|mut cpu| cpu.run());
+        std::fs::write(&path, [NOP; NARROW_MEMORY_SIZE + 1]).unwrap();
+        let too_large = Cpu::from_binary(&path, AddressMode::Narrow).map(|_| ());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok(Err(CpuError::Exit(2))));
+        assert_eq!(too_large, Err(CpuError::MemoryError(NARROW_MEMORY_SIZE)));
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let file_handle = std::fs::File::open("./tests/fib.as").unwrap();
+        let mut assembler = asm::assembler::Assembler::new(file_handle);
+        let nbytes = match assembler.parse() {
+            Ok(nbytes) => nbytes,
+            Err(e) => panic!("Encountered an error parsing \"./tests/fib.as\": {:?}", e),
+        };
+
+        eprintln!("Assembled {} bytes from \"./tests/fib.as\"", nbytes);
+        let mut cpu = cpu::Cpu::new();
+        cpu.memory = memory::Memory::new_with_instructions(&assembler.get_output());
+
+        match cpu.run() {
+            Err(error::CpuError::Exit(exit_code)) => {
+                if exit_code == 1 {
+                    eprintln!("Exited correctly");
+                } else {
+                    unreachable!();
+                }
+            }
+            Err(e) => panic!("{:?}", e),
+            Ok(()) => (),
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut cpu = Cpu::builder()
+            .accumulator(5)
+            .user(6)
+            .program(&[ADD, STA, 0x40, 0, EXIT, 3])
+            .load(0x40, &[0xFF, 0xEE])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.memory.get(0x41), Ok(0xEE));
+        assert_eq!(cpu.run(), Err(CpuError::Exit(3)));
+        assert_eq!(cpu.memory.get(0x40), Ok(11));
+
+        let cpu = Cpu::builder().load(0xFFF0, &[0; 0x20]).build();
+        assert!(matches!(cpu, Err(CpuError::MemoryError(_))));
+    }
+
+    #[test]
+    fn test_output_sinks() {
+        let program = [SETV, 7, CLN, OUT, INC, OUT, EXIT, 0];
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .output(move |value| sender.send(value).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(receiver.try_iter().collect::<Vec<u8>>(), vec![7, 8]);
+
+        let written = io::Buffer::new();
+        let sink = written.clone();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .output(io::Writer(Vec::new()))
+            .build()
+            .unwrap();
+        cpu.set_output(sink);
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(written.contents(), vec![7, 8]);
+
+        let mut lines = Vec::new();
+        io::Writer(&mut lines).output(9).unwrap();
+        assert_eq!(lines, b"9\n");
+    }
+
+    #[test]
+    fn test_input() {
+        let program = Assembler::assemble(
+            "
+    nop
+echo:
+    in
+    jo done ;; end of input
+    out
+    jmp echo
+done:
+    exit 0
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .input(io::Queue::new(&[3, 0, 255]))
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![3, 0, 255]);
+
+        let lines = "12\n\n0x10\n  0b11 \n";
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .input(io::Reader(lines.as_bytes()))
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![12, 16, 3]);
+
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .input(io::Reader("256\n".as_bytes()))
+            .build()
+            .unwrap();
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::InputError(std::io::ErrorKind::InvalidData))
+        );
+
+        let source = Disassembler::new(vec![IN, OUT]).disassemble().unwrap();
+        assert!(source.contains("IN "));
+        assert_eq!(Assembler::assemble(&source).unwrap(), vec![IN, OUT]);
+    }
+
+    #[test]
+    fn test_trace_records() {
+        use trace::Change;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut cpu = Cpu::builder()
+            .program(&[SETV, 7, STR, 0x40, 0, LDA, 0x40, 0, INC, EXIT, 2])
+            .tracer(move |record: &TraceRecord| sender.send(record.clone()).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(2)));
+
+        let records: Vec<TraceRecord> = receiver.try_iter().collect();
+        let ips: Vec<u16> = records.iter().map(|record| record.ip).collect();
+        assert_eq!(ips, vec![0, 2, 5, 8, 9]);
+        assert_eq!(records[0].changes, vec![Change::User(0, 7)]);
+        assert_eq!(records[1].changes, vec![Change::Memory(0x40, 0, 7)]);
+        assert_eq!(records[2].instruction, Instruction::LDA(0x40));
+        assert_eq!(records[2].changes, vec![Change::Accumulator(0, 7)]);
+        assert_eq!(records[3].changes, vec![Change::Accumulator(7, 8)]);
+        assert_eq!(records[4].instruction, Instruction::EXIT(2));
+
+        // Writes to a device are traced too, with a before only where it can be peeked
+        struct Latch(u8);
+        impl Device for Latch {
+            fn read(&mut self, _offset: u16) -> u8 {
+                self.0
+            }
+            fn write(&mut self, _offset: u16, value: u8) {
+                self.0 = value;
+            }
+            fn peek(&self, offset: u16) -> Option<u8> {
+                Some(self.0).filter(|_| offset == 0)
+            }
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut cpu = Cpu::builder()
+            .program(&[SETV, 7, STR, 0x00, 0xF0, STR, 0x01, 0xF0, EXIT, 0])
+            .device(0xF000..=0xF001, Latch(3))
+            .tracer(move |record: &TraceRecord| sender.send(record.clone()).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        let records: Vec<TraceRecord> = receiver.try_iter().collect();
+        assert_eq!(records[1].changes, vec![Change::Device(0xF000, Some(3), 7)]);
+        assert_eq!(records[2].changes, vec![Change::Device(0xF001, None, 7)]);
+        assert_eq!(
+            records[1].to_string(),
+            "0x0002  STR 61440  device [0xF000] 3->7"
+        );
+        let write = "{\"addr\":61441,\"before\":null,\"after\":7,\"device\":true}";
+        assert!(records[2].to_json().contains(write));
+    }
+
+    #[test]
+    fn test_trace_writer() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let program = [SETV, 7, STR, 0x40, 0, LDA, 0x40, 0, INC, EXIT, 2];
+        let text = Shared::default();
+        let mut cpu = Cpu::builder().program(&program).build().unwrap();
+        cpu.set_tracer(TraceWriter::new(text.clone(), TraceFormat::Text).range(2..=5));
+        assert_eq!(cpu.run(), Err(CpuError::Exit(2)));
+        let text = String::from_utf8(text.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "0x0002  STR 64     [0x0040] 0->7\n0x0005  LDA 64     acc 0->7\n"
+        );
+
+        let json = Shared::default();
+        let mut cpu = Cpu::builder().program(&program).build().unwrap();
+        cpu.set_tracer(TraceWriter::new(json.clone(), TraceFormat::JsonLines));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // Turning tracing off at runtime
+        assert!(cpu.take_tracer().is_some());
+        assert_eq!(cpu.run(), Err(CpuError::Exit(2)));
+        let json = String::from_utf8(json.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            json,
+            "{\"ip\":0,\"instruction\":\"SETV 7\",\"usr\":[0,7]}\n\
+             {\"ip\":2,\"instruction\":\"STR 64\",\"writes\":[{\"addr\":64,\"before\":0,\"after\":7}]}\n"
+        );
+    }
+
+    #[test]
+    fn test_run_limits() {
+        let mut cpu = Cpu::builder()
+            .program(&[SETV, 1, ADD, EXIT, 4])
+            .build()
+            .unwrap();
+        assert_eq!(
+            cpu.run_for(100),
+            RunReport {
+                stop: Stop::Exit(4),
+                steps: 3
+            }
+        );
+
+        // NOP, then JMP 0 resumes at the JMP itself forever
+        let program = [NOP, JMP, 0, 0];
+        let mut cpu = Cpu::builder().program(&program).build().unwrap();
+        assert_eq!(
+            cpu.run_for(100),
+            RunReport {
+                stop: Stop::BudgetExhausted,
+                steps: 100
+            }
+        );
+        // A run can be resumed where the budget left off
+        assert_eq!(cpu.run_for(0).steps, 0);
+        assert_eq!(cpu.ip, 1);
+
+        let mut cpu = Cpu::builder().program(&program).build().unwrap();
+        let report = cpu.run_for_duration(std::time::Duration::from_millis(20));
+        assert_eq!(report.stop, Stop::TimedOut);
+        assert!(report.steps > 0);
+
+        let mut cpu = Cpu::builder().program(&[0xEE, 0]).build().unwrap();
+        let report = cpu.run_with(Limits {
+            max_steps: Some(10),
+            timeout: Some(std::time::Duration::from_secs(10)),
+        });
+        assert_eq!(report.stop, Stop::Fault(CpuError::MalformedInput(0xEE)));
+        assert_eq!(report.steps, 1);
+    }
+
+    #[test]
+    fn test_stack() {
+        let program = Assembler::assemble(
+            "
+    SETV 3
+    CLN
+    CALL double
+    OUT
+    SETV 5
+    PUSH U
+    CALL double
+    OUT
+    POP A
+    OUT
+    EXIT 0
+double:
+    STA 0x40
+    LOAD 0x40
+    ADD
+    RET
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![6, 12, 5]);
+        assert_eq!(cpu.sp as usize, MEMORY_SIZE - 1);
+
+        let mut cpu = Cpu::builder()
+            .program(&[NOP, PUSHA, JMP, 0, 0])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::StackOverflow));
+        assert_eq!(cpu.sp as usize, MEMORY_SIZE - 1 - STACK_SIZE);
+
+        let mut cpu = Cpu::builder()
+            .program(&[SETV, 9, PUSHU, POPA, POPU])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::StackUnderflow));
+        assert_eq!(cpu.accumulator, 9);
+    }
+
+    #[test]
+    fn test_indexed_loop() {
+        // Print the array at 0x40 backwards, indexing with the user register counting down
+        let program = Assembler::assemble(
+            "
+    SETV 4
+loop:
+    LDA 0x3F,U
+    OUT
+    CLN
+    DEC
+    JZ done
+    STA 0x50
+    LOAD 0x50
+    JMP loop
+done:
+    EXIT 0
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .load(0x40, &[10, 20, 30, 40])
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![40, 30, 20, 10]);
+
+        // Copy the array forwards, the last index wrapping the base around to 0x40
+        let program = [
+            SETV, 0x04, LDAX, 0x3C, 0, SETV, 0x14, STAX, 0x3C, 0, SETV, 0x45, STRX, 0xFB, 0xFF,
+            EXIT, 0,
+        ];
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .load(0x40, &[10, 20, 30, 40])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(cpu.memory.get(0x50).unwrap(), 10);
+        assert_eq!(cpu.memory.get(0x40).unwrap(), 0x45);
+    }
+
+    #[test]
+    fn test_indirect_loop() {
+        // Walk a pointer at 0x30 along a zero terminated string
+        let program = Assembler::assemble(
+            "
+    NOP
+loop:
+    LDA [0x30]
+    JZ done
+    OUT
+    LDA 0x30
+    INC
+    STA 0x30
+    JMP loop
+done:
+    EXIT 0
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .load(0x30, &[0x40])
+            .load(0x40, b"hi!\0")
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), b"hi!".to_vec());
+        assert_eq!(cpu.memory.get(0x30).unwrap(), 0x43);
+
+        // Pointers are little-endian addresses
+        let program = [
+            SETV, 9, STRI, 0x30, 0, LOADI, 0x32, 0, SETAI, 0x32, 0, STAI, 0x32, 0, EXIT, 0,
+        ];
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .load(0x30, &[0x50, 0x01, 0x50, 0x01])
+            .accumulator(3)
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(cpu.user, 9);
+        assert_eq!(cpu.memory.get(0x150).unwrap(), 3);
+
+        // In compatibility mode a pointer past the end of memory faults like any other bad address
+        let mut cpu = Cpu::builder()
+            .mode(AddressMode::Narrow)
+            .program(&[LDAI, 0x30])
+            .load(0x30, &[0xFF])
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::MemoryError(0xFF)));
+    }
+
+    #[test]
+    fn test_memory_mapped_io() {
+        use std::sync::{Arc, Mutex};
+
+        // A serial port: reading 0 counts up, writing 1 sends a byte
+        #[derive(Clone, Default)]
+        struct Serial {
+            sent: Arc<Mutex<Vec<u8>>>,
+            reads: u8,
+        }
+        impl Device for Serial {
+            fn read(&mut self, offset: u16) -> u8 {
+                match offset {
+                    0 => {
+                        self.reads += 1;
+                        self.reads
+                    }
+                    _ => 0,
+                }
+            }
+            fn write(&mut self, offset: u16, value: u8) {
+                if offset == 1 {
+                    self.sent.lock().unwrap().push(value);
+                }
+            }
+        }
+
+        let serial = Serial::default();
+        let program = Assembler::assemble(
+            "
+    LDA 0xF000
+    STA 0xF001
+    LDA 0xF000
+    STA 0xF001
+    LDA [0x0200]
+    STA 0xF001
+    LDA 0x0300
+    STA 0xF001
+    EXIT 0
+",
+        )
+        .unwrap();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .rom(0x0200, &[0x00, 0xF0])
+            .rom(0x0300, &[42])
+            .device(0xF000..=0xF0FF, serial.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(*serial.sent.lock().unwrap(), vec![1, 2, 3, 42]);
+        // Device reads and writes never reach the backing storage
+        assert_eq!(cpu.memory.peek(0xF001), None);
+        cpu.memory.map_ram(0xF000..=0xF0FF);
+        assert_eq!(cpu.memory.get(0xF001), Ok(0));
+
+        // ROM can be read but not written
+        let mut cpu = Cpu::builder()
+            .program(&[STA, 0x01, 0x03, EXIT, 0])
+            .rom(0x0300, &[42, 43])
+            .accumulator(7)
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::ReadOnly(0x0301)));
+        assert_eq!(cpu.memory.get(0x0301), Ok(43));
+
+        // ROM may fill all of memory, but not run past it
+        let mut cpu = Cpu::builder().rom(0, &[0; 0x10000]).build().unwrap();
+        assert_eq!(cpu.memory.set(0xFFFF, 1), Err(CpuError::ReadOnly(0xFFFF)));
+        assert!(matches!(
+            Cpu::builder().rom(1, &[0; 0x10000]).build(),
+            Err(CpuError::MemoryError(0x10000))
+        ));
+
+        // A later mapping shadows an earlier one
+        let mut memory = Memory::new();
+        memory.map_rom(0x1000..=0x1FFF);
+        memory.map_ram(0x1800..=0x18FF);
+        assert_eq!(memory.set(0x1800, 1), Ok(()));
+        assert_eq!(memory.set(0x1900, 1), Err(CpuError::ReadOnly(0x1900)));
+    }
+
+    #[test]
+    fn test_bitwise_ops() {
+        // (opcode, acc, user) -> (acc, zero, carry)
+        let cases = [
+            (AND, 0b1100_0101, 0b1010_1010, 0b1000_0000, false, false),
+            (AND, 0b0101_0101, 0b1010_1010, 0, true, false),
+            (OR, 0b1100_0101, 0b1010_1010, 0b1110_1111, false, false),
+            (XOR, 0b1100_0101, 0b1010_1010, 0b0110_1111, false, false),
+            (XOR, 0x5A, 0x5A, 0, true, false),
+            (NOT, 0b1100_0101, 1, 0b0011_1010, false, false),
+            (NOT, 0xFF, 1, 0, true, false),
+            (SHL, 0b0100_0001, 1, 0b1000_0010, false, false),
+            (SHL, 0b1000_0000, 1, 0, true, true),
+            (SHR, 0b1000_0010, 1, 0b0100_0001, false, false),
+            (SHR, 0b0000_0011, 1, 0b0000_0001, false, true),
+            (ROL, 0b1000_0001, 1, 0b0000_0011, false, true),
+            (ROR, 0b1000_0001, 1, 0b1100_0000, false, true),
+            (ROR, 0b0000_0010, 1, 0b0000_0001, false, false),
+        ];
+        for (op, acc, user, result, zero, carry) in cases {
+            let mut cpu = Cpu::builder()
+                .program(&[op])
+                .accumulator(acc)
+                .user(user)
+                .build()
+                .unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.accumulator, result, "opcode 0x{:02X} on {}", op, acc);
+            assert_eq!(cpu.flags.zero, zero, "zero after 0x{:02X} on {}", op, acc);
+            assert_eq!(
+                cpu.flags.carry, carry,
+                "carry after 0x{:02X} on {}",
+                op, acc
+            );
+        }
+
+        // The carry out of a shift is visible to the next instruction
+        let program = [SHL, JCS, 5, 0, EXIT, 1, EXIT, 2];
+        for (acc, code) in [(0x81, 2), (0x01, 1)] {
+            let mut cpu = Cpu::builder()
+                .program(&program)
+                .accumulator(acc)
+                .user(1)
+                .build()
+                .unwrap();
+            assert_eq!(cpu.run(), Err(CpuError::Exit(code)));
+        }
+    }
+
+    #[test]
+    fn test_compare_and_jump() {
+        type Condition = fn(u8, u8) -> bool;
+        let jumps: [(u8, Condition); 12] = [
+            (JC, |a, b| a == b),
+            (JCS, |a, b| a < b),
+            (JZ, |a, b| a == b),
+            (JNZ, |a, b| a != b),
+            (JNO, |_, _| true),
+            (JNC, |a, b| a >= b),
+            (JN, |a, b| a.wrapping_sub(b) & 0x80 != 0),
+            (JNN, |a, b| a.wrapping_sub(b) & 0x80 == 0),
+            (JLT, |a, b| a < b),
+            (JGE, |a, b| a >= b),
+            (JGT, |a, b| a > b),
+            (JLE, |a, b| a <= b),
+        ];
+        let pairs = [
+            (3, 5),
+            (5, 3),
+            (5, 5),
+            (0, 5),
+            (5, 0),
+            (0, 0),
+            (200, 10),
+            (10, 200),
+        ];
+
+        for (jump, condition) in jumps {
+            for (acc, user) in pairs {
+                // Taken jumps resume at the second EXIT
+                let mut cpu = Cpu::builder()
+                    .program(&[CMP, jump, 5, 0, EXIT, 0, EXIT, 1])
+                    .accumulator(acc)
+                    .user(user)
+                    .build()
+                    .unwrap();
+                let expected = CpuError::Exit(condition(acc, user) as u8);
+                assert_eq!(
+                    cpu.run(),
+                    Err(expected),
+                    "CMP {} with {} then 0x{:02X}",
+                    acc,
+                    user,
+                    jump
+                );
+            }
+        }
+
+        // Flags from an earlier instruction don't leak into the comparison
+        let mut cpu = Cpu::builder()
+            .program(&[INC, CMP, JNZ, 6, 0, EXIT, 0, EXIT, 1])
+            .accumulator(255)
+            .user(1)
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(1)));
+        assert!(!cpu.flags.overflow);
+    }
+
+    #[test]
+    fn test_mul_div_mod() {
+        let program = Assembler::assemble(
+            "
+    SETV 12
+    CLN
+    SETV 10
+    MUL
+    OUT
+    SETV 7
+    DIV
+    OUT
+    SETV 5
+    MOD
+    OUT
+    EXIT 0
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![120, 17, 2]);
+
+        // The product wraps and raises overflow
+        let mut cpu = Cpu::builder()
+            .program(&[MUL, JO, 5, 0, EXIT, 0, EXIT, 1])
+            .accumulator(20)
+            .user(13)
+            .build()
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 4);
+        assert!(cpu.flags.overflow);
+        assert_eq!(cpu.run(), Err(CpuError::Exit(1)));
+
+        for op in [DIV, MOD] {
+            let mut cpu = Cpu::builder()
+                .program(&[op])
+                .accumulator(9)
+                .build()
+                .unwrap();
+            assert_eq!(cpu.run(), Err(CpuError::DivideByZero));
+            assert_eq!(cpu.accumulator, 9);
+        }
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let program = Assembler::assemble(
+            "
+    EI
+    SETV 1
+    CLN
+    OUT
+    INC
+    OUT
+    EXIT 0
+handler:
+    PUSH A
+    SETV 99
+    CLN
+    OUT
+    POP A
+    IRET
+",
+        )
+        .unwrap();
+        let output = io::Buffer::new();
+        let mut cpu = Cpu::builder()
+            .program(&program)
+            // `handler` resolves to 8, like any jump target
+            .load(VECTOR_TABLE as u16 + 4, &[8, 0])
+            .output(output.clone())
+            .build()
+            .unwrap();
+        // Raised before the program enables interrupts, so serviced right after `EI`
+        cpu.raise_irq(2);
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(output.contents(), vec![99, 1, 2]);
+        assert_eq!(cpu.irq.pending(), 0);
+        assert_eq!(cpu.sp as usize, MEMORY_SIZE - 1);
+
+        // Masked lines stay pending
+        let mut cpu = Cpu::builder().program(&[DI, NOP, EXIT, 0]).build().unwrap();
+        cpu.flags.interrupt_enable = true;
+        cpu.step().unwrap();
+        assert!(!cpu.flags.interrupt_enable);
+        let lines = cpu.irq_lines();
+        std::thread::spawn(move || lines.raise(5)).join().unwrap();
+        assert_eq!(cpu.run(), Err(CpuError::Exit(0)));
+        assert_eq!(cpu.irq.pending(), 1 << 5);
+
+        // IRET restores ip and the flags the handler clobbered
+        let mut cpu = Cpu::builder()
+            .program(&[NOP, EXIT, 0])
+            .load(0x20, &[SETV, 0, IRET])
+            .load(VECTOR_TABLE as u16, &[0x1F, 0])
+            .load(VECTOR_TABLE as u16 + 14, &[0x1F, 0])
+            .build()
+            .unwrap();
+        cpu.flags.interrupt_enable = true;
+        cpu.flags.carry = true;
+        cpu.raise_irq(7);
+        cpu.raise_irq(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip, 0x22);
+        assert!(cpu.flags.zero && !cpu.flags.interrupt_enable);
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip, 0);
+        assert_eq!(
+            cpu.flags,
+            Flags {
+                carry: true,
+                interrupt_enable: true,
+                ..Flags::default()
+            }
+        );
+        assert_eq!(cpu.irq.pending(), 1 << 7);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip, 0);
+        assert_eq!(cpu.irq.pending(), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip, 1);
+        assert!(cpu.flags.carry);
+    }
+
+    #[test]
+    fn test_flag_conformance() {
+        // Each program runs until it leaves its own bytes. The flags it should write are listed
+        // as Z, N, V, C and I, upper case if set and lower case if cleared; the rest must keep
+        // their value, whatever it was.
+        #[rustfmt::skip]
+        let cases: &[(&[u8], u8, u8, &str)] = &[
+            (&[NOP], 1, 1, ""),
+            (&[LDA, 0x40, 0], 1, 1, "zN"),
+            (&[LDA, 0x42, 0], 1, 1, "Zn"),
+            (&[STA, 0x50, 0], 0, 0x80, ""),
+            (&[INC], 255, 1, "ZnV"),
+            (&[INC], 0x7F, 1, "zNv"),
+            (&[DEC], 0, 1, "zNV"),
+            (&[DEC], 1, 1, "Znv"),
+            (&[SETV, 0], 1, 1, "Zn"),
+            (&[SETA, 0x40, 0], 1, 1, "zN"),
+            (&[STR, 0x50, 0], 0, 0x80, ""),
+            (&[LOAD, 0x40, 0], 1, 1, "zN"),
+            (&[ADD], 200, 100, "znVC"),
+            (&[ADD], 128, 128, "ZnVC"),
+            (&[ADD], 1, 2, "znvc"),
+            (&[SUB], 3, 5, "zNvC"),
+            (&[SUB], 5, 5, "Znvc"),
+            (&[CMP], 5, 3, "znvc"),
+            (&[CMP], 3, 5, "zNvC"),
+            (&[JMP, 0x50, 0], 0, 0, ""),
+            (&[JC, 0x50, 0], 0, 0, ""),
+            (&[JCS, 0x50, 0], 0, 0, ""),
+            (&[JNZ, 0x50, 0], 0, 0, ""),
+            (&[JZ, 0x50, 0], 0, 0, ""),
+            (&[JO, 0x50, 0], 0, 0, ""),
+            (&[JNO, 0x50, 0], 0, 0, ""),
+            (&[JNC, 0x50, 0], 0, 0, ""),
+            (&[JN, 0x50, 0], 0, 0, ""),
+            (&[JNN, 0x50, 0], 0, 0, ""),
+            (&[JLT, 0x50, 0], 0, 0, ""),
+            (&[JGE, 0x50, 0], 0, 0, ""),
+            (&[JGT, 0x50, 0], 0, 0, ""),
+            (&[JLE, 0x50, 0], 0, 0, ""),
+            (&[OUT], 0, 0, ""),
+            (&[EXIT, 0], 0, 0, ""),
+            (&[CLN], 1, 0, "Zn"),
+            (&[IN], 0, 0, "zNv"),
+            (&[MUL], 16, 16, "ZnVC"),
+            (&[MUL], 3, 5, "znvc"),
+            (&[DIV], 9, 2, "znvc"),
+            (&[MOD], 9, 3, "Znvc"),
+            (&[EI], 0, 0, "I"),
+            (&[DI], 0, 0, "i"),
+            (&[SETV, 0, PUSHU, SETV, 0x50, PUSHU, SETV, 0b10101, PUSHU, IRET], 0, 0, "ZnvCI"),
+            (&[AND], 0xF0, 0x0F, "Zn"),
+            (&[OR], 0x80, 0x01, "zN"),
+            (&[XOR], 0x81, 0x81, "Zn"),
+            (&[NOT], 0x00, 0, "zN"),
+            (&[SHL], 0x81, 0, "znC"),
+            (&[SHL], 0x40, 0, "zNc"),
+            (&[SHR], 0x01, 0, "ZnC"),
+            (&[SHR], 0x02, 0, "znc"),
+            (&[ROL], 0x80, 0, "znC"),
+            (&[ROR], 0x01, 0, "zNC"),
+            (&[ROR], 0x02, 0, "znc"),
+            (&[PUSHA], 0, 0, ""),
+            (&[PUSHU], 0, 0, ""),
+            (&[PUSHU, POPA], 1, 0x80, "zN"),
+            (&[PUSHA, POPU], 0, 1, "Zn"),
+            (&[CALL, 0x50, 0], 0, 0, ""),
+            (&[PUSHU, PUSHU, RET], 0, 0x50, ""),
+            (&[LDAI, 0x41, 0], 1, 1, "zN"),
+            (&[STAI, 0x43, 0], 0, 0x80, ""),
+            (&[SETAI, 0x41, 0], 1, 1, "zN"),
+            (&[STRI, 0x43, 0], 0, 0x80, ""),
+            (&[LOADI, 0x41, 0], 1, 1, "zN"),
+            (&[LDAX, 0x3F, 0], 1, 1, "zN"),
+            (&[STAX, 0x50, 0], 0, 0x80, ""),
+            (&[SETAX, 0x3F, 0], 1, 1, "zN"),
+            (&[STRX, 0x50, 0], 0, 0x80, ""),
+            (&[LOADX, 0x41, 0], 1, 1, "Zn"),
+            (&[MOV, 0x18], 0x80, 0, "zN"),
+            (&[MOVI, 0x02, 0], 1, 1, "Zn"),
+            (&[ADDR, 0x89], 200, 100, "znVC"),
+            (&[ADDR, 0x98], 1, 2, "znvc"),
+            (&[SUBR, 0x89], 3, 5, "zNvC"),
+            (&[CMPR, 0x98], 3, 5, "znvc"),
+            (&[ANDR, 0x89], 0xF0, 0x0F, "Zn"),
+            (&[ORR, 0x09], 0, 0x80, "zN"),
+            (&[XORR, 0x00], 1, 1, "Zn"),
+            (&[INCR, 0x08], 255, 1, "ZnV"),
+            (&[DECR, 0x03], 1, 1, "zNV"),
+        ];
+
+        let mut covered = std::collections::BTreeSet::new();
+        for (program, acc, user, written) in cases {
+            let mut offset = 0;
+            while offset < program.len() {
+                let instruction = match Instruction::from_byte(program[offset]) {
+                    Some(instruction) => instruction,
+                    None => Instruction::from_byte_and_arg(program[offset], 0).unwrap(),
+                };
+                covered.insert(program[offset]);
+                offset += instruction.as_bytes().len();
+            }
+
+            for initial in [false, true] {
+                let mut cpu = Cpu::builder()
+                    .program(program)
+                    .load(0x40, &[0x80, 0x40, 0x00, 0x50, 0x00])
+                    .accumulator(*acc)
+                    .user(*user)
+                    .input(io::Queue::new(&[0x90]))
+                    .output(io::Buffer::new())
+                    .build()
+                    .unwrap();
+                cpu.flags = Flags {
+                    zero: initial,
+                    overflow: initial,
+                    carry: initial,
+                    negative: initial,
+                    interrupt_enable: initial,
+                };
+                let mut expected = cpu.flags;
+                for flag in written.chars() {
+                    let set = flag.is_ascii_uppercase();
+                    match flag.to_ascii_uppercase() {
+                        'Z' => expected.zero = set,
+                        'N' => expected.negative = set,
+                        'V' => expected.overflow = set,
+                        'C' => expected.carry = set,
+                        'I' => expected.interrupt_enable = set,
+                        _ => unreachable!(),
+                    }
+                }
+
+                while (cpu.ip as usize) < program.len() {
+                    match cpu.step() {
+                        Ok(()) => (),
+                        Err(CpuError::Exit(_)) => break,
+                        Err(e) => panic!("{:?} faulted: {}", program, e),
+                    }
+                }
+                assert_eq!(cpu.flags, expected, "{:?} from {}", program, initial);
+            }
+        }
+
+        // Every instruction has a case
+        for byte in 0..=u8::MAX {
+            if Instruction::from_byte(byte).is_some()
+                || Instruction::from_byte_and_arg(byte, 0).is_ok()
+            {
+                assert!(covered.contains(&byte), "no case for 0x{:02X}", byte);
+            }
+        }
+
+        // At the end of input, IN raises overflow
+        let mut cpu = Cpu::builder()
+            .program(&[IN])
+            .accumulator(1)
+            .input(io::Queue::new(&[]))
+            .build()
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.flags,
+            Flags {
+                zero: true,
+                overflow: true,
+                ..Flags::default()
+            }
+        );
+    }
+}
@@ -1,131 +1,413 @@
-pub const MEMORY_SIZE: usize = 255;
-pub const DEBUG: bool = false;
+use {
+    cpu::{
+        AddressMode, Assembler, AssemblerError, CompileError, Compiler, Cpu, CpuError, Debugger,
+        Disassembler, DisassemblerError, Limits, Stop, TraceFormat, TraceWriter,
+    },
+    std::{
+        fs::File,
+        io::{BufWriter, Read, Write},
+        ops::RangeInclusive,
+        path::{Path, PathBuf},
+        process,
+        time::Duration,
+    },
+};
 
-pub(crate) mod asm;
-pub(crate) mod cpu;
-pub(crate) mod error;
-pub(crate) mod flags;
-pub(crate) mod instruction;
-pub(crate) mod memory;
+const USAGE: &str = "\
+usage: cpu <command> [args]
 
-fn main() -> Result<(), error::CpuError> {
-    let mut cpu = cpu::Cpu::from_binary("./tests/fib.bin").unwrap();
-    cpu.run()?;
-    Ok(())
+commands:
+    assemble <in.as> [-o <out.bin>]  Assemble a source file into a binary image
+    compile <in.ln> [-o <out.as>]    Compile a .ln program into assembler source
+    run <file> [options]             Run a .as source file or a binary image
+    debug [--compat] <file>          Like `run`, but under the interactive debugger
+    disasm [--compat] <file.bin>     Disassemble a binary image back into source
+
+run options:
+    --compat                         Run an 8-bit binary built before the 16-bit address bus
+    --trace <text|json>              Trace every executed instruction to stderr
+    --trace-range <start>-<end>      Only trace instructions in this address range
+    --trace-output <path>            Write the trace to a file instead of stderr
+    --max-steps <n>                  Stop after executing n instructions
+    --timeout <seconds>              Stop after running for this long
+
+exit status:
+    run exits with the program's EXIT code, 1 if the cpu faults or the driver fails, 2 for
+    bad arguments and 124 if --max-steps or --timeout stopped the program";
+
+/// Exit code used when the command line itself is invalid
+const EXIT_USAGE: i32 = 2;
+/// Exit code used when the driver fails before or while running a program
+const EXIT_FAILURE: i32 = 1;
+/// Exit code used when `--max-steps` or `--timeout` stopped the program, as `timeout(1)` does
+const EXIT_LIMIT: i32 = 124;
+
+#[derive(Debug)]
+enum DriverError {
+    Usage(String),
+    Io(PathBuf, std::io::Error),
+    Assembler(PathBuf, AssemblerError),
+    Disassembler(PathBuf, DisassemblerError),
+    Compiler(PathBuf, CompileError),
+    TooLarge(PathBuf, usize, usize),
+    Cpu(CpuError, u16),
+    Limit(Stop, u64),
+}
+impl std::fmt::Display for DriverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DriverError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            DriverError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            DriverError::Assembler(path, e) => write!(f, "{}: {}", path.display(), e),
+            DriverError::Disassembler(path, e) => write!(f, "{}: {}", path.display(), e),
+            DriverError::Compiler(path, e) => write!(f, "{}: {}", path.display(), e),
+            DriverError::TooLarge(path, len, size) => write!(
+                f,
+                "{}: image is {} bytes, but memory only holds {}",
+                path.display(),
+                len,
+                size
+            ),
+            DriverError::Cpu(e, ip) => write!(f, "cpu fault at 0x{:04X}: {}", ip, e),
+            DriverError::Limit(Stop::TimedOut, steps) => {
+                write!(f, "timed out after {} steps", steps)
+            }
+            DriverError::Limit(_, steps) => {
+                write!(f, "step budget exhausted after {} steps", steps)
+            }
+        }
+    }
+}
+
+impl DriverError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            DriverError::Usage(_) => EXIT_USAGE,
+            DriverError::Limit(..) => EXIT_LIMIT,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match dispatch(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            e.exit_code()
+        }
+    };
+    process::exit(code);
+}
+
+fn dispatch(args: &[String]) -> Result<i32, DriverError> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err(DriverError::Usage("no command given".to_owned())),
+    };
+
+    match command {
+        "assemble" => {
+            let (input, output) = match rest {
+                [input] => (PathBuf::from(input), Path::new(input).with_extension("bin")),
+                [input, flag, output] if flag == "-o" => (input.into(), output.into()),
+                _ => return Err(DriverError::Usage("bad arguments to `assemble`".to_owned())),
+            };
+            assemble(&input, &output)
+        }
+        "compile" => {
+            let (input, output) = match rest {
+                [input] => (PathBuf::from(input), Path::new(input).with_extension("as")),
+                [input, flag, output] if flag == "-o" => (input.into(), output.into()),
+                _ => return Err(DriverError::Usage("bad arguments to `compile`".to_owned())),
+            };
+            compile(&input, &output)
+        }
+        "run" => run(&RunOptions::parse(rest)?),
+        "debug" => match rest {
+            [file] => debug(Path::new(file), AddressMode::Wide),
+            [flag, file] if flag == "--compat" => debug(Path::new(file), AddressMode::Narrow),
+            _ => Err(DriverError::Usage("`debug` takes exactly one file".to_owned())),
+        },
+        "disasm" => match rest {
+            [file] => disassemble(Path::new(file), AddressMode::Wide),
+            [flag, file] if flag == "--compat" => disassemble(Path::new(file), AddressMode::Narrow),
+            _ => Err(DriverError::Usage("`disasm` takes exactly one file".to_owned())),
+        },
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        _ => Err(DriverError::Usage(format!("unknown command {:?}", command))),
+    }
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == extension)
+}
+
+fn open(path: &Path) -> Result<File, DriverError> {
+    File::open(path).map_err(|e| DriverError::Io(path.to_owned(), e))
+}
+
+fn parse_source(path: &Path) -> Result<Assembler, DriverError> {
+    let mut assembler = Assembler::open(path).map_err(|e| DriverError::Io(path.to_owned(), e))?;
+    assembler
+        .parse()
+        .map_err(|e| DriverError::Assembler(path.to_owned(), e))?;
+    Ok(assembler)
+}
+
+fn read_binary(path: &Path, mode: AddressMode) -> Result<Vec<u8>, DriverError> {
+    let mut bytes = Vec::new();
+    open(path)?
+        .read_to_end(&mut bytes)
+        .map_err(|e| DriverError::Io(path.to_owned(), e))?;
+    if bytes.len() > mode.memory_size() {
+        return Err(DriverError::TooLarge(
+            path.to_owned(),
+            bytes.len(),
+            mode.memory_size(),
+        ));
+    }
+    Ok(bytes)
+}
+
+fn assemble(input: &Path, output: &Path) -> Result<i32, DriverError> {
+    let assembler = parse_source(input)?;
+    assembler
+        .output_to_file(output)
+        .map_err(|e| DriverError::Io(output.to_owned(), e))?;
+    Ok(0)
+}
+
+fn compile(input: &Path, output: &Path) -> Result<i32, DriverError> {
+    let source = Compiler::new(open(input)?)
+        .compile()
+        .map_err(|e| DriverError::Compiler(input.to_owned(), e))?;
+    std::fs::write(output, source).map_err(|e| DriverError::Io(output.to_owned(), e))?;
+    Ok(0)
+}
+
+/// Sources are always assembled for the 16-bit machine, `mode` only applies to binaries
+fn load(path: &Path, mode: AddressMode) -> Result<Cpu, DriverError> {
+    let mode = if has_extension(path, "as") {
+        AddressMode::Wide
+    } else {
+        mode
+    };
+    let image = if has_extension(path, "as") {
+        parse_source(path)?.get_output()
+    } else {
+        read_binary(path, mode)?
+    };
+    Cpu::builder()
+        .mode(mode)
+        .program(&image)
+        .build()
+        .map_err(|e| DriverError::Cpu(e, 0))
+}
+
+struct RunOptions {
+    path: PathBuf,
+    trace: Option<TraceFormat>,
+    trace_range: RangeInclusive<u16>,
+    trace_output: Option<PathBuf>,
+    limits: Limits,
+    mode: AddressMode,
+}
+impl RunOptions {
+    fn parse(args: &[String]) -> Result<Self, DriverError> {
+        let usage = |msg: &str| DriverError::Usage(format!("run: {}", msg));
+
+        let mut path = None;
+        let mut trace = None;
+        let mut trace_range = 0..=u16::MAX;
+        let mut trace_output = None;
+        let mut limits = Limits::default();
+        let mut mode = AddressMode::Wide;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--compat" => mode = AddressMode::Narrow,
+                "--trace" => {
+                    trace = match args.next().map(String::as_str) {
+                        Some("text") => Some(TraceFormat::Text),
+                        Some("json") => Some(TraceFormat::JsonLines),
+                        _ => return Err(usage("--trace takes `text` or `json`")),
+                    }
+                }
+                "--trace-range" => {
+                    let range = args
+                        .next()
+                        .and_then(|range| range.split_once('-'))
+                        .and_then(|(start, end)| Some(parse_address(start)?..=parse_address(end)?));
+                    trace_range = match range {
+                        Some(range) => range,
+                        None => return Err(usage("--trace-range takes <start>-<end>")),
+                    };
+                }
+                "--trace-output" => match args.next() {
+                    Some(output) => trace_output = Some(PathBuf::from(output)),
+                    None => return Err(usage("--trace-output takes a path")),
+                },
+                "--max-steps" => match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => limits.max_steps = Some(n),
+                    None => return Err(usage("--max-steps takes a number")),
+                },
+                "--timeout" => match args.next().and_then(|secs| secs.parse::<f64>().ok()) {
+                    Some(secs) if secs.is_finite() && secs >= 0.0 => {
+                        limits.timeout = Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => return Err(usage("--timeout takes a number of seconds")),
+                },
+                flag if flag.starts_with("--") => {
+                    return Err(usage(&format!("unknown option {:?}", flag)))
+                }
+                file if path.is_none() => path = Some(PathBuf::from(file)),
+                _ => return Err(usage("takes exactly one file")),
+            }
+        }
+
+        match path {
+            Some(path) => Ok(Self {
+                path,
+                trace,
+                trace_range,
+                trace_output,
+                limits,
+                mode,
+            }),
+            None => Err(usage("no file given")),
+        }
+    }
+}
+
+fn parse_address(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn run(options: &RunOptions) -> Result<i32, DriverError> {
+    let mut cpu = load(&options.path, options.mode)?;
+
+    if let Some(format) = options.trace {
+        let sink: Box<dyn Write + Send> = match &options.trace_output {
+            Some(path) => Box::new(BufWriter::new(
+                File::create(path).map_err(|e| DriverError::Io(path.to_owned(), e))?,
+            )),
+            None => Box::new(std::io::stderr()),
+        };
+        cpu.set_tracer(TraceWriter::new(sink, format).range(options.trace_range.clone()));
+    }
+
+    let report = cpu.run_with(options.limits);
+    // Dropping the tracer flushes a buffered trace file
+    cpu.take_tracer();
+    match report.stop {
+        Stop::Exit(code) => Ok(code as i32),
+        Stop::Fault(e) => Err(DriverError::Cpu(e, cpu.ip)),
+        stop => Err(DriverError::Limit(stop, report.steps)),
+    }
+}
+
+fn debug(path: &Path, mode: AddressMode) -> Result<i32, DriverError> {
+    let mut debugger = Debugger::new(load(path, mode)?);
+    let stdin = std::io::stdin();
+    debugger
+        .repl(stdin.lock(), std::io::stdout())
+        .map_err(|e| DriverError::Io(path.to_owned(), e))?;
+    Ok(debugger.exit_code().map_or(0, |code| code as i32))
+}
+
+fn disassemble(path: &Path, mode: AddressMode) -> Result<i32, DriverError> {
+    let source = Disassembler::new(read_binary(path, mode)?)
+        .with_mode(mode)
+        .disassemble()
+        .map_err(|e| DriverError::Disassembler(path.to_owned(), e))?;
+    print!("{}", source);
+    Ok(0)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    pub const NOP: u8 = 0x00;
-    pub const LDA: u8 = 0x01;
-    pub const STA: u8 = 0x02;
-    pub const INC: u8 = 0x03;
-    pub const DEC: u8 = 0x04;
-    pub const SETV: u8 = 0x05;
-    pub const SETA: u8 = 0x06;
-    pub const STR: u8 = 0x07;
-    pub const LOAD: u8 = 0x08;
-    pub const ADD: u8 = 0x09;
-    pub const SUB: u8 = 0x0A;
-    pub const JMP: u8 = 0x0B;
-    pub const JC: u8 = 0x0C;
-    pub const JZ: u8 = 0x0E;
-    pub const JO: u8 = 0x0F;
-    pub const OUT: u8 = 0x10;
-    pub const EXIT: u8 = 0x11;
-    pub const CLN: u8 = 0x12;
+    /// A file in the temp dir, named after this process so concurrent test runs don't share it,
+    /// and removed when dropped
+    struct TempFile(PathBuf);
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpu_test_{}_{}", process::id(), name)))
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
 
     #[test]
-    fn test_acc_ops() {
-        let mut cpu = cpu::Cpu::new();
-        cpu.memory = memory::Memory::new_with_instructions(&vec![
-            SETV, 10, STR, 0x40, LDA, 0x40, SETV, 1, ADD, OUT, EXIT, 0,
-        ]);
-
-        match cpu.run() {
-            Err(error::CpuError::Exit(code)) => assert_eq!(code, 0),
-            Err(e) => panic!("{:?}", e),
-            _ => (),
-        };
-        assert_eq!(11, cpu.accumulator);
+    fn test_driver_assemble_then_run() {
+        let output = TempFile::new("driver_fib.bin");
+        let output_str = output.0.to_str().unwrap().to_owned();
+
+        let args = vec![
+            "assemble".to_owned(),
+            "./tests/fib.as".to_owned(),
+            "-o".to_owned(),
+            output_str.clone(),
+        ];
+        assert_eq!(dispatch(&args).unwrap(), 0);
+
+        // fib.as exits with code 1 once z overflows
+        let args = vec!["run".to_owned(), output_str];
+        assert_eq!(dispatch(&args).unwrap(), 1);
+        let args = vec!["run".to_owned(), "./tests/fib.as".to_owned()];
+        assert_eq!(dispatch(&args).unwrap(), 1);
     }
 
     #[test]
-    fn test_load_store() {
-        let mut cpu = cpu::Cpu::new();
-        println!("Expected output: 0, 10, 11");
-        cpu.memory = memory::Memory::new_with_instructions(&vec![
-            OUT, // -> 0
-            SETV, 10, STR, 0x40, LDA, 0x40, OUT, // -> 10
-            INC, STA, 0x40, LOAD, 0x40, CLN, OUT, // -> 11
-            EXIT, 0,
-        ]);
-        assert_eq!(cpu.run(), Err(error::CpuError::Exit(0)));
+    fn test_driver_compat() {
+        let output = TempFile::new("driver_compat.bin");
+        let output_str = output.0.to_str().unwrap().to_owned();
+
+        // SETV 5; STR 0x50; LDA 0x50; EXIT 3 in the 8-bit encoding
+        std::fs::write(&output.0, [0x05, 5, 0x07, 0x50, 0x01, 0x50, 0x11, 3]).unwrap();
+        let args = vec!["run".to_owned(), "--compat".to_owned(), output_str.clone()];
+        assert_eq!(dispatch(&args).unwrap(), 3);
+        // Read as a 16-bit binary it runs off the rails instead
+        let args = vec!["run".to_owned(), output_str];
+        assert!(matches!(dispatch(&args), Err(DriverError::Cpu(..))));
     }
 
     #[test]
-    fn test_fib() {
-        let mut cpu = cpu::Cpu::new();
-        cpu.memory = memory::Memory::new_with_instructions(&vec![
-            // init:
-            SETV, 0, STR, 0x40, // x = 0
-            STR, 0x42, // z = 0
-            SETV, 1, STR, 0x41, // y = 1
-            // loop
-
-            // print z
-            LDA, 0x42, OUT, // z = x + y
-            LDA, 0x40, // load x into acc
-            LOAD, 0x41, // load y into usr
-            ADD,  // add y to acc (x) -> acc = x + y
-            JO, 31, // exit if overflow
-            STA, 0x42, // store acc in z
-            // x = y
-            LDA, 0x41, // load y into acc
-            STA, 0x40, // store y in x
-            // y = z
-            LDA, 0x42, // load z into acc
-            STA, 0x41, // store z in y
-            // while z < 255
-            JMP, 9, // reenter the loop otherwise
-            // exit_good:
-            EXIT, 1,
-        ]);
-
-        match cpu.run() {
-            Err(error::CpuError::Exit(1)) => eprintln!("Exited correctly"),
-            Err(error::CpuError::Exit(code)) => panic!("Exited with code {}", code),
-            Err(e) => panic!("{:?}", e),
-            _ => (),
-        };
+    fn test_driver_usage_errors() {
+        assert!(matches!(dispatch(&[]), Err(DriverError::Usage(_))));
+        let args = vec!["frobnicate".to_owned()];
+        assert!(matches!(dispatch(&args), Err(DriverError::Usage(_))));
+        let args = vec!["run".to_owned()];
+        assert!(matches!(dispatch(&args), Err(DriverError::Usage(_))));
+        assert_eq!(dispatch(&args).unwrap_err().exit_code(), EXIT_USAGE);
     }
 
     #[test]
-    fn test_assemble_and_run() {
-        let file_handle = std::fs::File::open("./tests/fib.as").unwrap();
-        let mut assembler = asm::assembler::Assembler::new(file_handle);
-        let nbytes = match assembler.parse() {
-            Ok(nbytes) => nbytes,
-            Err(e) => panic!("Encountered an error parsing \"./tests/fib.as\": {:?}", e),
-        };
-
-        eprintln!("Assembled {} bytes from \"./tests/fib.as\"", nbytes);
-        let mut cpu = cpu::Cpu::new();
-        cpu.memory = memory::Memory::new_with_instructions(&assembler.get_output());
-
-        match cpu.run() {
-            Err(error::CpuError::Exit(exit_code)) => {
-                if exit_code == 1 {
-                    eprintln!("Exited correctly");
-                    return;
-                } else {
-                    unreachable!();
-                }
-            }
-            Err(e) => panic!("{:?}", e),
-            Ok(()) => return,
-        }
+    fn test_driver_limits() {
+        let args = vec![
+            "run".to_owned(),
+            "--max-steps".to_owned(),
+            "5".to_owned(),
+            "./tests/fib.as".to_owned(),
+        ];
+        let error = dispatch(&args).unwrap_err();
+        assert!(matches!(error, DriverError::Limit(Stop::BudgetExhausted, 5)));
+        // Apart from both EXIT 1 and a fault
+        assert_eq!(error.exit_code(), EXIT_LIMIT);
+        assert_eq!(DriverError::Cpu(CpuError::StackOverflow, 0).exit_code(), EXIT_FAILURE);
     }
 }
@@ -1,77 +1,218 @@
 use crate::error::CpuError;
+use crate::trace::Change;
 use crate::MEMORY_SIZE;
+use std::ops::RangeInclusive;
 
+/// A peripheral mapped into the address space. Offsets are relative to the start of the range
+/// the device is mapped at, so the same device can live anywhere.
+pub trait Device {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// The value a read would return, without any side effect it has, for debuggers and dumps.
+    /// `None` if the device can't tell without reading.
+    fn peek(&self, _offset: u16) -> Option<u8> {
+        None
+    }
+}
+
+/// What an address range is backed by
+enum Backing {
+    Ram,
+    /// Loaded like RAM, but writes from the program fault
+    Rom,
+    Device(Box<dyn Device + Send>),
+}
+
+struct Region {
+    range: RangeInclusive<u16>,
+    backing: Backing,
+}
+
+/// The address bus. Everything is RAM until a range is mapped to ROM or a device; a mapping
+/// shadows whatever was mapped over the same addresses before it. Every `u16` is a valid
+/// address, the narrower memory of `AddressMode::Narrow` is enforced by the cpu.
 pub struct Memory {
-    internal: [u8; MEMORY_SIZE],
+    internal: Box<[u8]>,
+    regions: Vec<Region>,
+    journal: Option<Vec<Change>>,
 }
 impl Memory {
-    fn is_valid_idx(idx: u8) -> Result<(), CpuError> {
-        if idx as usize >= MEMORY_SIZE {
-            Err(CpuError::MemoryError(idx))
-        } else {
-            Ok(())
-        }
-    }
-
     pub fn new() -> Self {
         Self {
-            internal: [0; MEMORY_SIZE],
+            internal: vec![0; MEMORY_SIZE].into_boxed_slice(),
+            regions: Vec::new(),
+            journal: None,
         }
     }
 
-    pub fn new_with_instructions(instr: &Vec<u8>) -> Self {
-        let mut internal = [0; MEMORY_SIZE];
-        if instr.len() >= 0xFF {
-            panic!("Memory overflow when initializing memory with instructions")
-        }
-        Self::is_valid_idx(instr.len() as u8)
+    pub fn new_with_instructions(instr: &[u8]) -> Self {
+        let mut memory = Self::new();
+        memory
+            .load(0, instr)
             .expect("Memory overflow when initializing memory with instructions");
-        unsafe {
-            std::ptr::copy(instr.as_ptr(), &mut internal[0] as *mut u8, instr.len());
+        memory
+    }
+
+    /// Back `range` with plain storage again, undoing earlier mappings
+    pub fn map_ram(&mut self, range: RangeInclusive<u16>) {
+        self.map(range, Backing::Ram);
+    }
+
+    /// Make `range` read-only. Its contents come from `load`, writes from the program fault.
+    pub fn map_rom(&mut self, range: RangeInclusive<u16>) {
+        self.map(range, Backing::Rom);
+    }
+
+    /// Hand every read and write in `range` to `device`
+    pub fn map_device<D: Device + Send + 'static>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: D,
+    ) {
+        self.map_boxed_device(range, Box::new(device));
+    }
+
+    pub(crate) fn map_boxed_device(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: Box<dyn Device + Send>,
+    ) {
+        self.map(range, Backing::Device(device));
+    }
+
+    fn map(&mut self, range: RangeInclusive<u16>, backing: Backing) {
+        self.regions.push(Region { range, backing });
+    }
+
+    /// The most recent mapping covering `idx`, or `None` for RAM that was never remapped
+    fn region(&mut self, idx: u16) -> Option<&mut Region> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|region| region.range.contains(&idx))
+    }
+
+    /// Copy `bytes` into storage starting at `offset`, leaving everything else untouched.
+    /// This is how ROM gets its contents; ranges mapped to a device stay shadowed by it.
+    pub fn load(&mut self, offset: u16, bytes: &[u8]) -> Result<(), CpuError> {
+        let end = offset as usize + bytes.len();
+        if end > MEMORY_SIZE {
+            // The first address past the end of memory
+            return Err(CpuError::MemoryError(MEMORY_SIZE));
         }
+        self.internal[offset as usize..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Start recording every write, to storage as `Change::Memory` and to a device as
+    /// `Change::Device`
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
 
-        Self { internal }
+    /// Stop recording and return the writes made since `start_journal`
+    pub fn take_journal(&mut self) -> Vec<Change> {
+        self.journal.take().unwrap_or_default()
     }
 
-    pub fn set(&mut self, idx: u8, v: u8) -> Result<(), CpuError> {
-        Self::is_valid_idx(idx)?;
-        self.internal[idx as usize] = v;
+    pub fn set(&mut self, idx: u16, v: u8) -> Result<(), CpuError> {
+        let change = match self.region(idx) {
+            Some(Region {
+                backing: Backing::Rom,
+                ..
+            }) => return Err(CpuError::ReadOnly(idx)),
+            Some(Region {
+                range,
+                backing: Backing::Device(device),
+            }) => {
+                let offset = idx - range.start();
+                let before = device.peek(offset);
+                device.write(offset, v);
+                Change::Device(idx, before, v)
+            }
+            _ => {
+                let before = std::mem::replace(&mut self.internal[idx as usize], v);
+                Change::Memory(idx, before, v)
+            }
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(change);
+        }
         Ok(())
     }
 
-    pub fn get(&self, idx: u8) -> Result<u8, CpuError> {
-        Self::is_valid_idx(idx)?;
-        Ok(self.internal[idx as usize])
+    pub fn get(&mut self, idx: u16) -> Result<u8, CpuError> {
+        match self.region(idx) {
+            Some(Region {
+                range,
+                backing: Backing::Device(device),
+            }) => Ok(device.read(idx - range.start())),
+            _ => Ok(self.internal[idx as usize]),
+        }
+    }
+
+    /// Like `get`, but without triggering a device. `None` if the device can't be peeked.
+    pub fn peek(&self, idx: u16) -> Option<u8> {
+        let region = self
+            .regions
+            .iter()
+            .rev()
+            .find(|region| region.range.contains(&idx));
+        match region {
+            Some(Region {
+                range,
+                backing: Backing::Device(device),
+            }) => device.peek(idx - range.start()),
+            _ => Some(self.internal[idx as usize]),
+        }
+    }
+}
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 impl std::fmt::Debug for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Memory {{\n\t\t{}\n\t}}", {
-            let mut lines: Vec<String> = Vec::new();
-
-            let even_lines = MEMORY_SIZE % 8 == 0;
-            let num_lines = ((MEMORY_SIZE - (MEMORY_SIZE % 8)) / 8) - 1;
-
-            for offs in (0..num_lines).map(|offs| offs * 8) {
-                lines.push(
-                    self.internal[offs..offs + 8]
-                        .iter()
-                        .map(|byte| format!("{:02X}", byte))
-                        .collect::<Vec<String>>()
-                        .join("  "),
-                );
-            }
-            if !even_lines {
-                lines.push(
-                    self.internal[num_lines + 1 * 8..]
-                        .iter()
-                        .map(|byte| format!("{:02X}", byte))
-                        .collect::<Vec<String>>()
-                        .join("  "),
-                );
-            }
-
-            lines.join(",\n\t\t")
-        })
+        let regions = self
+            .regions
+            .iter()
+            .map(|region| {
+                let kind = match region.backing {
+                    Backing::Ram => "ram",
+                    Backing::Rom => "rom",
+                    Backing::Device(_) => "device",
+                };
+                format!(
+                    "{} 0x{:04X}-0x{:04X}",
+                    kind,
+                    region.range.start(),
+                    region.range.end()
+                )
+            })
+            .collect::<Vec<String>>();
+        // Only lines holding something, 64 KiB of mostly zeroes isn't worth printing
+        write!(
+            f,
+            "Memory {{\n\t\tmapped: [{}]\n\t\t{}\n\t}}",
+            regions.join(", "),
+            self.internal
+                .chunks(8)
+                .enumerate()
+                .filter(|(_, line)| line.iter().any(|byte| *byte != 0))
+                .map(|(idx, line)| {
+                    format!(
+                        "0x{:04X}: {}",
+                        idx * 8,
+                        line.iter()
+                            .map(|byte| format!("{:02X}", byte))
+                            .collect::<Vec<String>>()
+                            .join("  ")
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(",\n\t\t")
+        )
     }
 }
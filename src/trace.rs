@@ -0,0 +1,174 @@
+use crate::instruction::Instruction;
+use std::{io::Write, ops::RangeInclusive};
+
+/// A state change caused by a single instruction, as `(before, after)` where relevant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Accumulator(u8, u8),
+    User(u8, u8),
+    /// `(index, before, after)` of a general-purpose register
+    Register(u8, u8, u8),
+    StackPointer(u16, u16),
+    Zero(bool, bool),
+    Overflow(bool, bool),
+    Carry(bool, bool),
+    Negative(bool, bool),
+    /// `(address, before, after)`
+    Memory(u16, u8, u8),
+    /// `(address, before, after)` of a write to a device, with a before only if it can `peek`
+    Device(u16, Option<u8>, u8),
+}
+
+/// One executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub ip: u16,
+    pub instruction: Instruction,
+    pub changes: Vec<Change>,
+}
+impl TraceRecord {
+    pub fn to_json(&self) -> String {
+        let changes = self
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::Accumulator(before, after) => format!("\"acc\":[{},{}]", before, after),
+                Change::User(before, after) => format!("\"usr\":[{},{}]", before, after),
+                Change::Register(idx, before, after) => {
+                    format!("\"r{}\":[{},{}]", idx, before, after)
+                }
+                Change::StackPointer(before, after) => format!("\"sp\":[{},{}]", before, after),
+                Change::Zero(before, after) => format!("\"zero\":[{},{}]", before, after),
+                Change::Overflow(before, after) => {
+                    format!("\"overflow\":[{},{}]", before, after)
+                }
+                Change::Carry(before, after) => format!("\"carry\":[{},{}]", before, after),
+                Change::Negative(before, after) => {
+                    format!("\"negative\":[{},{}]", before, after)
+                }
+                Change::Memory(..) | Change::Device(..) => String::new(),
+            })
+            .filter(|field| !field.is_empty())
+            .collect::<Vec<String>>();
+        let writes = self
+            .changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::Memory(addr, before, after) => Some(format!(
+                    "{{\"addr\":{},\"before\":{},\"after\":{}}}",
+                    addr, before, after
+                )),
+                Change::Device(addr, before, after) => Some(format!(
+                    "{{\"addr\":{},\"before\":{},\"after\":{},\"device\":true}}",
+                    addr,
+                    before.map_or("null".to_owned(), |before| before.to_string()),
+                    after
+                )),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let mut fields = vec![
+            format!("\"ip\":{}", self.ip),
+            format!("\"instruction\":\"{}\"", String::from(self.instruction)),
+        ];
+        fields.extend(changes);
+        if !writes.is_empty() {
+            fields.push(format!("\"writes\":[{}]", writes.join(",")));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+impl std::fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:04X}  ", self.ip)?;
+        if self.changes.is_empty() {
+            return write!(f, "{}", String::from(self.instruction));
+        }
+        write!(f, "{:<10}", String::from(self.instruction))?;
+        for change in self.changes.iter() {
+            match change {
+                Change::Accumulator(before, after) => write!(f, " acc {}->{}", before, after)?,
+                Change::User(before, after) => write!(f, " usr {}->{}", before, after)?,
+                Change::Register(idx, before, after) => {
+                    write!(f, " r{} {}->{}", idx, before, after)?
+                }
+                Change::StackPointer(before, after) => write!(f, " sp {}->{}", before, after)?,
+                Change::Zero(before, after) => {
+                    write!(f, " zero {}->{}", *before as u8, *after as u8)?
+                }
+                Change::Overflow(before, after) => {
+                    write!(f, " overflow {}->{}", *before as u8, *after as u8)?
+                }
+                Change::Carry(before, after) => {
+                    write!(f, " carry {}->{}", *before as u8, *after as u8)?
+                }
+                Change::Negative(before, after) => {
+                    write!(f, " negative {}->{}", *before as u8, *after as u8)?
+                }
+                Change::Memory(addr, before, after) => {
+                    write!(f, " [0x{:04X}] {}->{}", addr, before, after)?
+                }
+                Change::Device(addr, Some(before), after) => {
+                    write!(f, " device [0x{:04X}] {}->{}", addr, before, after)?
+                }
+                Change::Device(addr, None, after) => {
+                    write!(f, " device [0x{:04X}] ->{}", addr, after)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives a record for every instruction the cpu executes
+pub trait Tracer {
+    fn trace(&mut self, record: &TraceRecord);
+}
+
+/// Any `FnMut(&TraceRecord)` can be used as a tracer
+impl<F: FnMut(&TraceRecord)> Tracer for F {
+    fn trace(&mut self, record: &TraceRecord) {
+        self(record)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+/// Writes one line per record, optionally only for instructions within an address range
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    range: RangeInclusive<u16>,
+}
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W, format: TraceFormat) -> Self {
+        Self {
+            writer,
+            format,
+            range: 0..=u16::MAX,
+        }
+    }
+
+    /// Only trace instructions whose address is in `range`
+    pub fn range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.range = range;
+        self
+    }
+}
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if !self.range.contains(&record.ip) {
+            return;
+        }
+        // Tracing is best effort, a broken trace sink shouldn't stop the program
+        let _ = match self.format {
+            TraceFormat::Text => writeln!(self.writer, "{}", record),
+            TraceFormat::JsonLines => writeln!(self.writer, "{}", record.to_json()),
+        };
+    }
+}
@@ -1,34 +1,39 @@
+    .data 0x40
+    .var x
+    .var y
+    .var z
+
 init:
     setv 0
-    str 0x40 ;; x = 0
-    str 0x42 ;; z = 0
+    str x
+    str z
     setv 1
-    str 0x41 ;; y = 1
+    str y
 
 loop:
     ;; print z
-    lda 0x42
+    lda z
     out
 
     ;; z = x + y
-    lda 0x40    ;; load x into acc
-    load 0x41   ;; load y into usr
+    lda x       ;; load x into acc
+    load y      ;; load y into usr
     add         ;; add y to acc (x) -> acc = x + y
     jo exit_good ;; exit if overflow
-    sta 0x42    ;; store acc in z
+    sta z       ;; store acc in z
 
 
     ;; x = y
-    lda 0x41    ;; load y into acc
-    sta 0x40    ;; store y in x
+    lda y       ;; load y into acc
+    sta x       ;; store y in x
 
     ;; y = z
-    lda 0x42    ;; load z into acc
-    sta 0x41    ;; store z in y
+    lda z       ;; load z into acc
+    sta y       ;; store z in y
 
 
     ;; while z < 255
     jmp loop    ;; reenter the loop otherwise
 
 exit_good:
-    exit 1
\ No newline at end of file
+    exit 1
@@ -0,0 +1,3 @@
+    NOP
+    .include "lib/cells.as"
+    .include "lib/bad.as"
@@ -0,0 +1,6 @@
+;; Both libraries include cells.as, which is only assembled once
+    .include "lib/swap.as"
+    .include "lib/copy.as"
+    swap CELL, CELL+1
+    copy CELL, CELL+2
+    EXIT 0
@@ -0,0 +1,2 @@
+;; Assembles to nothing but an error
+    BOGUS
@@ -0,0 +1 @@
+    .equ CELL 0x40
@@ -0,0 +1,6 @@
+;; Copy the value at x to y
+    .include "cells.as"
+.macro copy x, y
+    LDA x
+    STA y
+.endm
@@ -0,0 +1,8 @@
+;; Swap the values at x and y, through A and U
+    .include "cells.as"
+.macro swap x, y
+    LDA x
+    LOAD y
+    STA y
+    STR x
+.endm
@@ -0,0 +1,4 @@
+;; Swaps two cells with the macro from the shared library
+    .include "lib/swap.as"
+    swap CELL, CELL+1
+    EXIT 0